@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/catalog.rs"]
+#[allow(dead_code)]
+mod catalog;
+use catalog::CatHeader;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(header) = CatHeader::try_parse(data) {
+        assert_eq!(header.checksum.len(), 32);
+    }
+});
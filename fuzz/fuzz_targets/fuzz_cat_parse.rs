@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use url::Url;
+
+#[path = "../../src/catalog.rs"]
+#[allow(dead_code)]
+mod catalog;
+use catalog::Cat;
+
+// Parses entries one after another, the way `determine_tasks` does with a
+// decompressed catalog, and checks that every one stays inside its base.
+fuzz_target!(|data: &[u8]| {
+    let base_url = Url::parse("https://example.com/files/").unwrap();
+    let base_path = Path::new("/base");
+    let mut next = data;
+    while let Ok((cat, rem)) = Cat::try_parse(next, &base_url, base_path) {
+        assert!(cat.dst_path.starts_with(base_path));
+        assert!(rem.len() < next.len());
+        next = rem;
+    }
+});
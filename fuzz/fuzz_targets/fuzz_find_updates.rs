@@ -0,0 +1,42 @@
+#![no_main]
+
+use std::{cell::RefCell, path::Path, rc::Rc};
+
+use libfuzzer_sys::fuzz_target;
+use tracing::{debug, info};
+use url::Url;
+
+#[path = "../../src/catalog.rs"]
+#[allow(dead_code)]
+mod catalog;
+use catalog::*;
+
+#[path = "../../src/gui/mod.rs"]
+#[allow(dead_code)]
+mod gui;
+use gui::{Gui, mock::MockGui};
+
+#[path = "../../src/update_finder.rs"]
+#[allow(dead_code)]
+mod update_finder;
+use update_finder::find_updates;
+
+/// Enough for any reasonable index, without letting an infinite loop stall
+/// the fuzzer.
+const LUA_LIMIT: u64 = 100000;
+
+// Runs arbitrary bytes as the update index. It may fail in any way it likes,
+// but mustn't panic, and mustn't get anything past `--basedir`.
+fuzz_target!(|data: &[u8]| {
+    let gui: Rc<RefCell<dyn Gui>> = Rc::new(RefCell::new(MockGui::new(false)));
+    let basedir = Path::new("/nonexistent/tupdate-fuzz");
+    let url = Url::parse("https://example.com/index.lua").unwrap();
+    if let Ok((installs, deletes)) = find_updates(gui, data, url, LUA_LIMIT, Some(basedir), "stable") {
+        for (dir, _) in installs.iter() {
+            assert!(dir.starts_with(basedir), "{:?}", dir);
+        }
+        for dir in deletes.keys() {
+            assert!(dir.starts_with(basedir), "{:?}", dir);
+        }
+    }
+});
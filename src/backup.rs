@@ -0,0 +1,98 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// Keeps copies of the files that an update replaces or deletes, so that a
+/// failed update can be undone.
+pub struct Backup {
+    dir: PathBuf,
+    basedirs: Vec<PathBuf>,
+    /// Every path we've touched, in order, along with where its original
+    /// contents went (`None` if it didn't exist before we got to it).
+    touched: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+fn copy_recursively(src: &Path, dst: &Path) -> io::Result<()> {
+    if src.is_dir() {
+        std::fs::create_dir_all(dst)?;
+        for ent in std::fs::read_dir(src)? {
+            let ent = ent?;
+            copy_recursively(&ent.path(), &dst.join(ent.file_name()))?;
+        }
+        Ok(())
+    }
+    else {
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(src, dst).map(|_| ())
+    }
+}
+
+fn remove_anything(path: &Path) -> io::Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(x) if x.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(x) => Err(x),
+    };
+    match result {
+        Err(x) if x.kind() == io::ErrorKind::NotFound => Ok(()),
+        x => x,
+    }
+}
+
+impl Backup {
+    pub fn new(dir: PathBuf, basedirs: Vec<PathBuf>) -> Backup {
+        Backup { dir, basedirs, touched: vec![] }
+    }
+    /// Returns true if we haven't touched anything yet.
+    pub fn is_empty(&self) -> bool {
+        self.touched.is_empty()
+    }
+    /// Where inside the backup directory the original of `path` goes. Paths
+    /// keep their position relative to whichever base directory they're in.
+    fn backup_path_for(&self, path: &Path) -> PathBuf {
+        let relative: PathBuf = match self.basedirs.iter().find_map(|x| path.strip_prefix(x).ok()) {
+            Some(x) => x.to_path_buf(),
+            // shouldn't happen, but don't let it escape the backup directory
+            None => path.components().filter(|x| matches!(x, Component::Normal(_))).collect(),
+        };
+        self.dir.join(relative)
+    }
+    /// Call before replacing or deleting `path`. Copies whatever is there now
+    /// into the backup directory.
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        if self.touched.iter().any(|(x, _)| x == path) {
+            return Ok(())
+        }
+        if std::fs::symlink_metadata(path).is_err() {
+            self.touched.push((path.to_path_buf(), None));
+            return Ok(())
+        }
+        let backup_path = self.backup_path_for(path);
+        copy_recursively(path, &backup_path)?;
+        self.touched.push((path.to_path_buf(), Some(backup_path)));
+        Ok(())
+    }
+    /// Puts everything back the way it was, as best we can. Returns the first
+    /// error we ran into, along with the path involved, but keeps going
+    /// after it.
+    pub fn restore(&self) -> Result<(), (PathBuf, io::Error)> {
+        let mut ret = Ok(());
+        for (path, backup_path) in self.touched.iter().rev() {
+            let result = remove_anything(path).and_then(|_| {
+                match backup_path {
+                    Some(backup_path) => copy_recursively(backup_path, path),
+                    None => Ok(()),
+                }
+            });
+            if let Err(x) = result {
+                if ret.is_ok() {
+                    ret = Err((path.clone(), x));
+                }
+            }
+        }
+        ret
+    }
+}
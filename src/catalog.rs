@@ -0,0 +1,241 @@
+//! Parsing catalogs: their headers, and the entries inside them. Kept apart
+//! from everything else so that `fuzz/` can include it on its own.
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use url::Url;
+
+/// The longest path `is_fishy_path` will accept, in bytes.
+pub const MAX_PATH_LENGTH: usize = 4096;
+/// The longest single path component `is_fishy_path` will accept, in bytes.
+pub const MAX_PATH_COMPONENT_LENGTH: usize = 255;
+/// The smallest a catalog entry can be: a one-byte path and its newline,
+/// checksum, size, and extension length.
+pub const MIN_CAT_ENTRY_SIZE: usize = 2 + 32 + 8 + 2;
+
+pub fn is_fishy_path(target: &str) -> bool {
+    // Most filesystems won't take anything longer than these, and would give
+    // a much less helpful error about it.
+    if target.len() > MAX_PATH_LENGTH || target.contains('\0') { return true }
+    let mut components = target.split(['/', '\\']);
+    if components.any(|x| x == ".." || x.len() > MAX_PATH_COMPONENT_LENGTH) { return true }
+    // e.g. `C:/foo`, which is absolute on Windows even if it isn't here
+    let has_drive_letter = matches!(target.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+    has_drive_letter ||
+    target.starts_with(".") || target.starts_with("/") || target.starts_with("\\") || target.find("/.").is_some() || target.find("\\.").is_some()
+}
+
+/// Returns true if a symlink at `link_path` (relative to the base directory)
+/// pointing at `target` could lead outside the base directory.
+pub fn is_fishy_symlink(link_path: &str, target: &str) -> bool {
+    let is_separator = |c: char| c == '/' || c == '\\';
+    if target.is_empty() || target.starts_with(is_separator) || target.contains(':') { return true }
+    // How many directories deep we are, starting from the link's parent.
+    let mut depth = link_path.split(is_separator).filter(|x| !x.is_empty()).count() as isize - 1;
+    for component in target.split(is_separator) {
+        match component {
+            "" | "." => (),
+            ".." => {
+                depth -= 1;
+                if depth < 0 { return true }
+            },
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+/// `is_fishy_symlink` only looks at one link at a time. If a path passes
+/// through a directory that is itself one of the catalogs' symlinks, that
+/// link's target gets spliced into the path, and the depth counting no longer
+/// holds: `a/t -> ..` and `a/u -> t/..` each look fine alone, but `a/u` ends
+/// up outside the base directory. Returns the first entry whose path, or
+/// whose symlink target, goes through another symlink entry.
+pub fn find_chained_symlink(cats: &[Cat]) -> Option<&Cat> {
+    let symlinks: HashSet<&Path> = cats.iter()
+        .filter(|cat| matches!(cat.kind, CatKind::Symlink(_)))
+        .map(|cat| cat.dst_path.as_path())
+        .collect();
+    if symlinks.is_empty() { return None }
+    cats.iter().find(|cat| {
+        if cat.dst_path.ancestors().skip(1).any(|x| symlinks.contains(x)) { return true }
+        let target = match &cat.kind {
+            CatKind::Symlink(target) => target,
+            CatKind::File => return false,
+        };
+        // Pointing straight at another link (e.g. `libfoo.so ->
+        // libfoo.so.1`) is fine. Going through one isn't.
+        let mut resolved = cat.dst_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut components = target.components().peekable();
+        while let Some(component) = components.next() {
+            match component {
+                Component::Normal(x) => {
+                    resolved.push(x);
+                    if components.peek().is_some() && symlinks.contains(resolved.as_path()) { return true }
+                },
+                Component::ParentDir => { resolved.pop(); },
+                // `is_fishy_symlink` already rejected anything rooted.
+                _ => (),
+            }
+        }
+        false
+    })
+}
+
+/// What a catalog entry puts at its destination. Given by the bits above the
+/// executable flag in the first extension byte: 0 for a file, 1 for a
+/// symlink.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CatKind {
+    File,
+    /// The target follows the type byte, at the end of the extension area.
+    /// Its length is given in place of a size.
+    Symlink(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct Cat {
+    pub src_url: Url,
+    pub dst_path: PathBuf,
+    pub checksum: [u8; 32],
+    pub size: u64,
+    /// Bit 0 of the first extension byte. Whether the file should be
+    /// executable once it's in place. (Ignored on Windows.)
+    pub executable: bool,
+    pub kind: CatKind,
+    /// Bytes 1 through 8 of the extension area, if present: the modification
+    /// time to give the file, in nanoseconds since the Unix epoch.
+    pub mtime: Option<SystemTime>,
+    pub needs_download: bool,
+    /// If the local copy didn't match, why not.
+    pub mismatch: Option<Mismatch>,
+}
+
+/// The way in which a local file failed to match its catalog entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing,
+    Unreadable,
+    Size,
+    Checksum,
+    Target,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mismatch::Missing => write!(fmt, "missing"),
+            Mismatch::Unreadable => write!(fmt, "unreadable"),
+            Mismatch::Size => write!(fmt, "size mismatch"),
+            Mismatch::Checksum => write!(fmt, "checksum mismatch"),
+            Mismatch::Target => write!(fmt, "symlink target mismatch"),
+        }
+    }
+}
+
+impl Cat {
+    pub fn try_parse<'a>(bytes: &'a [u8], base_url: &Url, base_path: &Path) -> Result<(Cat, &'a [u8]), ()> {
+        let newline = bytes.iter().position(|x| *x == b'\n').ok_or(())?;
+        if newline == 0 { return Err(()) }
+        if bytes.len() < newline + 43 { return Err(()) }
+        let file_path = &bytes[..newline];
+        let file_path = std::str::from_utf8(file_path).map_err(|_| ())?;
+        let checksum = &bytes[newline+1 .. newline+33];
+        let size = u64::from_be_bytes(bytes[newline+33 .. newline+41].try_into().unwrap());
+        let xt = u16::from_be_bytes(bytes[newline+41 .. newline+43].try_into().unwrap());
+        let next = newline + 43 + xt as usize;
+        if next > bytes.len() { return Err(()) }
+        let extension = &bytes[newline+43 .. next];
+        let executable = extension.first().map(|x| x & 1 != 0).unwrap_or(false);
+        if is_fishy_path(file_path) { return Err(()) }
+        let (kind, size, fields) = match extension.first().map(|x| x >> 1).unwrap_or(0) {
+            0 => (CatKind::File, size, extension),
+            1 => {
+                if size > extension.len() as u64 - 1 { return Err(()) }
+                let target = &extension[extension.len() - size as usize ..];
+                let target = std::str::from_utf8(target).map_err(|_| ())?;
+                if is_fishy_symlink(file_path, target) { return Err(()) }
+                // Nothing to download.
+                (CatKind::Symlink(PathBuf::from(target)), 0, &extension[.. extension.len() - size as usize])
+            },
+            _ => return Err(()),
+        };
+        let mtime = fields.get(1..9).map(|x| {
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(x.try_into().unwrap()))
+        });
+        // is_fishy_path should have caught these already, but it's cheap to
+        // make sure.
+        if Path::new(file_path).components().any(|c| c == Component::ParentDir) { return Err(()) }
+        let dst_path = base_path.join(file_path);
+        if !dst_path.starts_with(base_path) { return Err(()) }
+        let src_url = base_url.join(file_path).map_err(|_| ())?;
+        Ok((Cat {
+            src_url,
+            dst_path,
+            checksum: checksum.try_into().unwrap(),
+            size,
+            executable,
+            kind,
+            mtime,
+            needs_download: false,
+            mismatch: None,
+        }, &bytes[next..]))
+    }
+}
+
+/// The header of a catalog file.
+///
+/// Version 1 catalogs begin with `\xFFTCat`, followed by the SHA-256 of the
+/// uncompressed entries and their size (`u32`). Later versions begin with
+/// `\xFFTCaV` and a version byte, then the same checksum and size. Version 2
+/// follows those with the number of entries (`u32`) and the total size of the
+/// files they describe (`u64`). Version 3 is the same as version 2, except
+/// that the entries are compressed with zstd instead of zlib. All numbers are
+/// big-endian. The rest of the file is the compressed entries.
+pub struct CatHeader<'a> {
+    pub checksum: &'a [u8],
+    pub uncompressed_size: usize,
+    pub entry_count: Option<u32>,
+    pub payload_bytes: Option<u64>,
+    pub compression: Compression,
+    pub compressed: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zlib,
+    /// Only readable with the `zstd-cats` feature.
+    Zstd,
+}
+
+impl CatHeader<'_> {
+    pub fn try_parse(body: &[u8]) -> Option<CatHeader<'_>> {
+        let (version, rest) = match body.get(..5)? {
+            b"\xFFTCat" => (1, &body[5..]),
+            b"\xFFTCaV" => (*body.get(5)?, &body[6..]),
+            _ => return None,
+        };
+        let (entry_count, payload_bytes, compressed) = match version {
+            1 => (None, None, rest.get(36..)?),
+            2 | 3 => {
+                let entry_count = u32::from_be_bytes(rest.get(36..40)?.try_into().unwrap());
+                let payload_bytes = u64::from_be_bytes(rest.get(40..48)?.try_into().unwrap());
+                (Some(entry_count), Some(payload_bytes), &rest[48..])
+            },
+            _ => return None,
+        };
+        let compression = if version >= 3 { Compression::Zstd } else { Compression::Zlib };
+        Some(CatHeader {
+            checksum: &rest[..32],
+            uncompressed_size: u32::from_be_bytes(rest[32..36].try_into().unwrap()) as usize,
+            entry_count,
+            payload_bytes,
+            compression,
+            compressed,
+        })
+    }
+}
@@ -0,0 +1,57 @@
+use super::*;
+
+/// Keeps the catalogs we've downloaded before, along with the `ETag` and
+/// `Last-Modified` headers they came with, so that we can ask the server to
+/// send them only if they've changed.
+pub struct CatalogCache {
+    dir: PathBuf,
+}
+
+/// A catalog from the cache.
+pub struct CachedCatalog {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl CatalogCache {
+    /// Returns `None` if this platform doesn't have a cache directory.
+    pub fn new() -> Option<CatalogCache> {
+        let dir = dirs::cache_dir()?.join(CONFIG_DIR_NAME);
+        debug!("Caching catalogs in: {:?}", dir);
+        Some(CatalogCache { dir })
+    }
+    fn path_for(&self, url: &Url) -> PathBuf {
+        let hash = lsx::sha256::hash(url.as_str().as_bytes());
+        self.dir.join(format!("{}.cache", hex::encode(hash)))
+    }
+    /// The file is the `ETag` on the first line, `Last-Modified` on the
+    /// second (either may be empty), and then the body.
+    pub fn load(&self, url: &Url) -> Option<CachedCatalog> {
+        let data = std::fs::read(self.path_for(url)).ok()?;
+        let mut parts = data.splitn(3, |x| *x == b'\n');
+        let etag = std::str::from_utf8(parts.next()?).ok()?;
+        let last_modified = std::str::from_utf8(parts.next()?).ok()?;
+        let body = parts.next()?.to_vec();
+        let non_empty = |x: &str| if x.is_empty() { None } else { Some(x.to_string()) };
+        Some(CachedCatalog { etag: non_empty(etag), last_modified: non_empty(last_modified), body })
+    }
+    /// Failing to cache something isn't worth bothering anyone about, so
+    /// errors only get logged.
+    pub fn store(&self, url: &Url, etag: Option<&str>, last_modified: Option<&str>, body: &[u8]) {
+        let path = self.path_for(url);
+        let tmp_path = temp_path_for(&path);
+        let result = std::fs::create_dir_all(&self.dir).and_then(|_| {
+            let mut f = File::create(&tmp_path)?;
+            writeln!(f, "{}", etag.unwrap_or(""))?;
+            writeln!(f, "{}", last_modified.unwrap_or(""))?;
+            f.write_all(body)?;
+            drop(f);
+            std::fs::rename(&tmp_path, &path)
+        });
+        if let Err(x) = result {
+            let _ = std::fs::remove_file(&tmp_path);
+            debug!("{}: couldn't cache catalog: {}", url, x);
+        }
+    }
+}
@@ -1,24 +1,75 @@
 use super::*;
 
-pub struct BatchGui;
+pub struct BatchGui {
+    /// Output newline-delimited JSON objects instead of human-readable text.
+    json: bool,
+    /// Write warnings and errors to stdout, like we used to, instead of stderr.
+    errors_to_stdout: bool,
+}
 
 impl Gui for BatchGui {
-    fn set_progress(&mut self, _task: &str, _subtask: &str, _progress: Option<f32>) {
+    fn set_progress(&mut self, task: &str, subtask: &str, progress: Option<f32>) {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "progress", "task": task, "subtask": subtask, "fraction": progress}));
+        }
+    }
+    fn do_message(&mut self, title: &str, message: &str) {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "message", "title": title, "message": message}));
+        }
+        else {
+            println!(": {}", message);
+        }
+    }
+    fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "warning", "title": title, "message": message, "can_cancel": can_cancel}));
+        }
+        else {
+            if self.errors_to_stdout { println!("? {}", message) }
+            else { eprintln!("? {}", message) }
+        }
+        true
+    }
+    fn do_error(&mut self, title: &str, message: &str) {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "error", "title": title, "message": message}));
+        }
+        else {
+            if self.errors_to_stdout { println!("! {}", message) }
+            else { eprintln!("! {}", message) }
+        }
     }
-    fn do_message(&mut self, _title: &str, message: &str) {
-        println!(": {}", message);
+    fn do_error_coded(&mut self, title: &str, message: &str, code: &str) {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "error", "title": title, "message": message, "code": code}));
+        }
+        else {
+            self.do_error(title, &format!("{}\n(Error code: {})", message, code));
+        }
     }
-    fn do_warning(&mut self, _title: &str, message: &str, _can_cancel: bool) -> bool {
-        println!("? {}", message);
+    fn do_question(&mut self, title: &str, question: &str) -> bool {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "question", "title": title, "question": question}));
+        }
+        else {
+            if self.errors_to_stdout { println!("? {}", question) }
+            else { eprintln!("? {}", question) }
+        }
         true
     }
-    fn do_error(&mut self, _title: &str, message: &str) {
-        println!("! {}", message);
+    fn verbose(&mut self, message: &str) {
+        if self.json {
+            println!("{}", serde_json::json!({"type": "verbose", "message": message}));
+        }
+        else {
+            eprintln!("{}", message);
+        }
     }
 }
 
 impl BatchGui {
-    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(_: Option<bool>, f: T) -> Result<ExitCode, T> {
-        Ok(f(Rc::new(RefCell::new(BatchGui))))
+    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(_: Option<bool>, json: bool, errors_to_stdout: bool, f: T) -> Result<ExitCode, T> {
+        Ok(f(Rc::new(RefCell::new(BatchGui { json, errors_to_stdout }))))
     }
 }
\ No newline at end of file
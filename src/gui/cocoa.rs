@@ -1,6 +1,7 @@
 use super::*;
 
 use std::{
+    collections::HashSet,
     process::ExitCode,
     sync::{Mutex, mpsc},
 };
@@ -18,7 +19,6 @@ use alertish::*;
 
 struct GuiApp {
     window: Mutex<Option<Window<GuiWindow>>>,
-    res_tx: mpsc::Sender<bool>,
 }
 
 #[derive(Default)]
@@ -28,8 +28,14 @@ struct GuiWindow {
     subtasklabel: Label,
     bar: ProgressIndicator,
     determinate: bool,
+    /// The title from `set_title`, if any, without any percentage.
+    title: Option<String>,
+    /// The percentage currently shown in the title, if any.
+    title_percent: Option<u32>,
 }
 
+const WINDOW_TITLE: &str = "Tejat Updater";
+
 const TOP_GAP: f64 = 16.0;
 const BAR_GAP: f64 = 12.0;
 const HGAP: f64 = 24.0;
@@ -74,27 +80,50 @@ impl Dispatcher for GuiApp {
                 if subtask != windel.subtasklabel.get_text() {
                     windel.subtasklabel.set_text(subtask);
                 }
+                let percent = progress.map(|x| (x.clamp(0.0, 1.0) * 100.0).floor() as u32);
+                if percent != windel.title_percent {
+                    windel.title_percent = percent;
+                    let title = windel.title.as_deref().unwrap_or(WINDOW_TITLE);
+                    let title = match percent {
+                        Some(percent) => format!("{} — {}%", title, percent),
+                        None => title.to_string(),
+                    };
+                    window.set_title(&title);
+                }
+            },
+            Request::SetTitle { title } => {
+                let shown = match windel.title_percent {
+                    Some(percent) => format!("{} — {}%", title, percent),
+                    None => title.clone(),
+                };
+                windel.title = Some(title);
+                window.set_title(&shown);
             },
-            Request::Message { title, message} => {
-                window.close();
+            // Alerts are sheets on the progress window, so it stays where it
+            // is. The answer goes back through the channel that came with the
+            // request, once the user gives it.
+            Request::Message { title, message, reply } => {
                 let alert = Alert::new(&title, &message, false, AlertStyle::Informational);
-                alert.run_modal();
-                window.show();
-                let _ = self.res_tx.send(true);
+                alert.begin_sheet(&window.objc, move |_, _| { let _ = reply.send(Reply::default()); });
             },
-            Request::Warning { title, message, can_cancel} => {
-                window.close();
+            Request::Warning { title, message, can_cancel, reply } => {
                 let alert = Alert::new(&title, &message, can_cancel, AlertStyle::Warning);
-                let response = alert.run_modal();
-                window.show();
-                let _ = self.res_tx.send(response == 1000);
+                // Only for warnings that can't be refused; quietly agreeing to
+                // something the user might want to cancel would be rude.
+                if !can_cancel {
+                    alert.set_suppression_button("Don't show this again");
+                }
+                alert.begin_sheet(&window.objc, move |alert, response| {
+                    let _ = reply.send(Reply { ok: response == 1000, suppress: alert.suppression_checked() });
+                });
+            },
+            Request::Question { title, question, reply } => {
+                let alert = Alert::new_question(&title, &question);
+                alert.begin_sheet(&window.objc, move |_, response| { let _ = reply.send(Reply { ok: response == 1000, suppress: false }); });
             },
-            Request::Error { title, message} => {
-                window.close();
+            Request::Error { title, message, reply } => {
                 let alert = Alert::new(&title, &message, false, AlertStyle::Error);
-                alert.run_modal();
-                window.show();
-                let _ = self.res_tx.send(true);
+                alert.begin_sheet(&window.objc, move |_, _| { let _ = reply.send(Reply::default()); });
             },
         }
     }
@@ -103,7 +132,7 @@ impl Dispatcher for GuiApp {
 impl WindowDelegate for GuiWindow {
     const NAME: &'static str = "GuiApp";
     fn did_load(&mut self, window: Window) {
-        window.set_title("Tejat Updater");
+        window.set_title(WINDOW_TITLE);
         self.tasklabel.set_text("Initializing...");
         self.subtasklabel.set_text_alignment(TextAlign::Right);
         self.bar.set_indeterminate(true);
@@ -131,44 +160,70 @@ impl WindowDelegate for GuiWindow {
 #[derive(Debug)]
 enum Request {
     SetProgress { task: String, subtask: String, progress: Option<f32> },
-    Message { title: String, message: String },
-    Warning { title: String, message: String, #[allow(dead_code)] can_cancel: bool },
-    Error { title: String, message: String },
+    SetTitle { title: String },
+    Message { title: String, message: String, reply: mpsc::Sender<Reply> },
+    Warning { title: String, message: String, can_cancel: bool, reply: mpsc::Sender<Reply> },
+    Question { title: String, question: String, reply: mpsc::Sender<Reply> },
+    Error { title: String, message: String, reply: mpsc::Sender<Reply> },
+}
+
+/// How the user dismissed an alert.
+#[derive(Debug, Default)]
+struct Reply {
+    /// True if they pressed OK or Yes.
+    ok: bool,
+    /// True if they asked not to see this warning again.
+    suppress: bool,
 }
 
 pub struct CocoaGui {
-    res_rx: mpsc::Receiver<bool>,
+    /// Titles of warnings the user doesn't want to see again.
+    suppressed: HashSet<String>,
 }
 
 impl CocoaGui {
     pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(_: Option<bool>, f: T) -> Result<ExitCode, T> {
-        let (res_tx, res_rx) = mpsc::channel();
         std::thread::spawn(move || {
-            f(Rc::new(RefCell::new(CocoaGui { res_rx })));
+            f(Rc::new(RefCell::new(CocoaGui { suppressed: HashSet::new() })));
             App::terminate();
         });
         App::new("net.tejat.tupdate", GuiApp {
-            res_tx,
             window: Mutex::new(None),
         }).run();
         Ok(ExitCode::SUCCESS)
     }
+    /// Shows an alert, and waits for the user to dismiss it.
+    fn ask(&mut self, request: impl FnOnce(mpsc::Sender<Reply>) -> Request) -> Reply {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        App::<GuiApp, Request>::dispatch_main(request(reply_tx));
+        reply_rx.recv().unwrap_or_default()
+    }
 }
 
 impl Gui for CocoaGui {
     fn set_progress(&mut self, task: &str, subtask: &str, progress: Option<f32>) {
         App::<GuiApp, Request>::dispatch_main(Request::SetProgress { task: task.to_string(), subtask: subtask.to_string(), progress });
     }
+    fn set_title(&mut self, title: &str) {
+        App::<GuiApp, Request>::dispatch_main(Request::SetTitle { title: title.to_string() });
+    }
     fn do_message(&mut self, title: &str, message: &str) {
-        App::<GuiApp, Request>::dispatch_main(Request::Message { title: title.to_string(), message: message.to_string() });
-        self.res_rx.recv().unwrap();
+        self.ask(|reply| Request::Message { title: title.to_string(), message: message.to_string(), reply });
     }
     fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
-        App::<GuiApp, Request>::dispatch_main(Request::Warning { title: title.to_string(), message: message.to_string(), can_cancel });
-        self.res_rx.recv().unwrap()
+        if assume_yes() { return true }
+        if !can_cancel && self.suppressed.contains(title) { return true }
+        let reply = self.ask(|reply| Request::Warning { title: title.to_string(), message: message.to_string(), can_cancel, reply });
+        if reply.suppress {
+            self.suppressed.insert(title.to_string());
+        }
+        reply.ok
+    }
+    fn do_question(&mut self, title: &str, question: &str) -> bool {
+        if assume_yes() { return true }
+        self.ask(|reply| Request::Question { title: title.to_string(), question: question.to_string(), reply }).ok
     }
     fn do_error(&mut self, title: &str, message: &str) {
-        App::<GuiApp, Request>::dispatch_main(Request::Error { title: title.to_string(), message: message.to_string() });
-        self.res_rx.recv().unwrap();
+        self.ask(|reply| Request::Error { title: title.to_string(), message: message.to_string(), reply });
     }
 }
\ No newline at end of file
@@ -1,10 +1,11 @@
 //! Modified version of the NSAlert wrapper from `cacao`.
 
+use block::ConcreteBlock;
 use objc::runtime::Object;
 use objc::{class, msg_send, sel, sel_impl};
 use objc_id::Id;
 
-use cacao::foundation::{id, NSInteger, NSString};
+use cacao::foundation::{id, NSInteger, NSString, YES};
 
 #[derive(Debug)]
 pub struct Alert(Id<Object>);
@@ -36,10 +37,52 @@ impl Alert {
         })
     }
 
-    /// Shows this alert as a modal, and return the response. 1000 = OK, 1001 = cancel.
-    pub fn run_modal(&self) -> NSInteger {
+    /// Makes a neutral alert with "Yes" and "No" buttons.
+    pub fn new_question(title: &str, question: &str) -> Self {
+        let title = NSString::new(title);
+        let question = NSString::new(question);
+        Alert(unsafe {
+            let alert: id = msg_send![class!(NSAlert), new];
+            let _: () = msg_send![alert, setMessageText: title];
+            let _: () = msg_send![alert, setInformativeText: question];
+            let _: () = msg_send![alert, addButtonWithTitle: NSString::new("Yes")];
+            let _: () = msg_send![alert, addButtonWithTitle: NSString::new("No")];
+            let _: () = msg_send![alert, setAlertStyle: 1];
+            Id::from_ptr(alert)
+        })
+    }
+
+    /// Adds a checkbox with the given label, e.g. "Don't show this again".
+    pub fn set_suppression_button(&self, label: &str) {
+        unsafe {
+            let _: () = msg_send![&*self.0, setShowsSuppressionButton: YES];
+            let button: id = msg_send![&*self.0, suppressionButton];
+            let _: () = msg_send![button, setTitle: NSString::new(label)];
+        }
+    }
+
+    /// Returns true if the checkbox from `set_suppression_button` is checked.
+    pub fn suppression_checked(&self) -> bool {
+        unsafe {
+            let button: id = msg_send![&*self.0, suppressionButton];
+            let state: NSInteger = msg_send![button, state];
+            state != 0
+        }
+    }
+
+    /// Shows this alert as a sheet on the given window, and returns right
+    /// away. `handler` gets the alert and the response once the user
+    /// dismisses it: 1000 for the first button (OK, Yes), 1001 for the second
+    /// (Cancel, No).
+    pub fn begin_sheet<F: Fn(&Alert, NSInteger) + 'static>(self, window: &Object, handler: F) {
+        let alert = &*self.0 as *const Object;
+        // The block keeps the alert alive until the sheet is done with it.
+        let block = ConcreteBlock::new(move |response: NSInteger| {
+            handler(&self, response)
+        });
+        let block = block.copy();
         unsafe {
-            msg_send![&*self.0, runModal]
+            let _: () = msg_send![alert, beginSheetModalForWindow: window completionHandler: &*block];
         }
     }
 }
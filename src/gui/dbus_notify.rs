@@ -0,0 +1,129 @@
+use super::*;
+
+use std::collections::HashMap;
+
+use zbus::{
+    blocking::{Connection, MessageIterator},
+    zvariant::Value,
+    MatchRule, MessageType,
+};
+
+const APP_NAME: &str = "Tejat Updater";
+const DESTINATION: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+const INTERFACE: &str = "org.freedesktop.Notifications";
+/// Urgency hint values, from the Desktop Notifications spec.
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+/// A "GUI" that uses desktop notifications, via D-Bus. Meant for systems
+/// where a notification daemon is listening but nobody is watching a
+/// terminal.
+pub struct DbusNotifyGui {
+    connection: Connection,
+    /// ID of the notification we're showing progress in, or 0 if there isn't
+    /// one yet.
+    progress_id: u32,
+}
+
+impl DbusNotifyGui {
+    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(_: Option<bool>, f: T) -> Result<ExitCode, T> {
+        let connection = match Connection::session() {
+            Ok(x) => x,
+            Err(_) => return Err(f),
+        };
+        // Make sure somebody is actually there to show our notifications.
+        if connection.call_method(Some(DESTINATION), PATH, Some(INTERFACE), "GetServerInformation", &()).is_err() {
+            return Err(f)
+        }
+        let gui = Rc::new(RefCell::new(DbusNotifyGui { connection, progress_id: 0 }));
+        let ret = f(gui.clone());
+        gui.borrow_mut().close_progress();
+        Ok(ret)
+    }
+    fn notify(&self, replaces_id: u32, summary: &str, body: &str, actions: &[&str], urgency: u8, expire_timeout: i32) -> zbus::Result<u32> {
+        let mut hints: HashMap<&str, Value> = HashMap::new();
+        hints.insert("urgency", Value::U8(urgency));
+        let reply = self.connection.call_method(Some(DESTINATION), PATH, Some(INTERFACE), "Notify", &(APP_NAME, replaces_id, "", summary, body, actions, hints, expire_timeout))?;
+        reply.body::<u32>()
+    }
+    fn close_progress(&mut self) {
+        if self.progress_id != 0 {
+            let _ = self.connection.call_method(Some(DESTINATION), PATH, Some(INTERFACE), "CloseNotification", &(self.progress_id,));
+            self.progress_id = 0;
+        }
+    }
+    /// Shows a notification, then waits until it's dismissed or one of its
+    /// actions is invoked. Returns the key of the action, or `None` if it was
+    /// just dismissed.
+    fn notify_and_wait(&self, summary: &str, body: &str, actions: &[&str], urgency: u8) -> zbus::Result<Option<String>> {
+        // Start listening before the notification exists, so we can't miss
+        // its signals.
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .interface(INTERFACE)?
+            .path(PATH)?
+            .build();
+        let messages = MessageIterator::for_match_rule(rule, &self.connection, None)?;
+        let id = self.notify(0, summary, body, actions, urgency, 0)?;
+        for message in messages {
+            let message = message?;
+            let member = match message.member() {
+                Some(x) => x,
+                None => continue,
+            };
+            match member.as_str() {
+                "ActionInvoked" => {
+                    let (signal_id, action_key) = message.body::<(u32, String)>()?;
+                    if signal_id == id {
+                        let _ = self.connection.call_method(Some(DESTINATION), PATH, Some(INTERFACE), "CloseNotification", &(id,));
+                        return Ok(Some(action_key))
+                    }
+                },
+                "NotificationClosed" => {
+                    let (signal_id, _reason) = message.body::<(u32, u32)>()?;
+                    if signal_id == id {
+                        return Ok(None)
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Gui for DbusNotifyGui {
+    fn set_progress(&mut self, task: &str, subtask: &str, progress: Option<f32>) {
+        let mut body = match progress {
+            Some(x) => format!("{:.0}% — {}", x.clamp(0.0, 1.0) * 100.0, task),
+            None => task.to_string(),
+        };
+        if subtask != "" {
+            body.push('\n');
+            body.push_str(subtask);
+        }
+        if let Ok(id) = self.notify(self.progress_id, APP_NAME, &body, &[], URGENCY_NORMAL, 0) {
+            self.progress_id = id;
+        }
+    }
+    fn do_message(&mut self, title: &str, message: &str) {
+        let _ = self.notify(0, title, message, &[], URGENCY_NORMAL, -1);
+    }
+    fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
+        if assume_yes() { return true }
+        if can_cancel {
+            match self.notify_and_wait(title, message, &["ok", "OK", "cancel", "Cancel"], URGENCY_CRITICAL) {
+                Ok(Some(x)) => x == "ok",
+                _ => false,
+            }
+        }
+        else {
+            let _ = self.notify_and_wait(title, message, &[], URGENCY_CRITICAL);
+            true
+        }
+    }
+    fn do_error(&mut self, title: &str, message: &str) {
+        let _ = self.notify(0, title, message, &[], URGENCY_CRITICAL, -1);
+    }
+}
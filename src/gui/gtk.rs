@@ -0,0 +1,177 @@
+use super::*;
+
+use std::{
+    cell::Cell,
+    process::ExitCode,
+    sync::mpsc,
+    time::Duration,
+};
+
+use gtk4 as gtk;
+use gtk::{glib, prelude::*};
+
+const WINDOW_TITLE: &str = "Tejat Updater";
+const WINDOW_WIDTH: i32 = 512;
+const GAP: i32 = 12;
+const HGAP: i32 = 24;
+/// How often the progress bar bounces when we don't know how far along we
+/// are.
+const PULSE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+enum Request {
+    SetProgress { task: String, subtask: String, progress: Option<f32> },
+    SetTitle { title: String },
+    Message { title: String, message: String },
+    Warning { title: String, message: String, can_cancel: bool },
+    Error { title: String, message: String },
+    Quit,
+}
+
+/// Lives on the main thread.
+struct GuiWindow {
+    window: gtk::Window,
+    tasklabel: gtk::Label,
+    subtasklabel: gtk::Label,
+    bar: gtk::ProgressBar,
+    determinate: Rc<Cell<bool>>,
+    res_tx: mpsc::Sender<bool>,
+}
+
+impl GuiWindow {
+    fn new(res_tx: mpsc::Sender<bool>) -> GuiWindow {
+        let window = gtk::Window::builder()
+            .title(WINDOW_TITLE)
+            .default_width(WINDOW_WIDTH)
+            .resizable(false)
+            .deletable(false)
+            .build();
+        let tasklabel = gtk::Label::new(Some("Initializing..."));
+        tasklabel.set_xalign(0.0);
+        let subtasklabel = gtk::Label::new(None);
+        subtasklabel.set_xalign(1.0);
+        let bar = gtk::ProgressBar::new();
+        let vbox = gtk::Box::new(gtk::Orientation::Vertical, GAP);
+        vbox.set_margin_top(GAP);
+        vbox.set_margin_bottom(GAP);
+        vbox.set_margin_start(HGAP);
+        vbox.set_margin_end(HGAP);
+        vbox.append(&tasklabel);
+        vbox.append(&subtasklabel);
+        vbox.append(&bar);
+        window.set_child(Some(&vbox));
+        let determinate = Rc::new(Cell::new(false));
+        {
+            let bar = bar.clone();
+            let determinate = determinate.clone();
+            glib::timeout_add_local(PULSE_INTERVAL, move || {
+                if !determinate.get() {
+                    bar.pulse();
+                }
+                glib::Continue(true)
+            });
+        }
+        window.present();
+        GuiWindow { window, tasklabel, subtasklabel, bar, determinate, res_tx }
+    }
+    fn show_dialog(&self, title: &str, message: &str, message_type: gtk::MessageType, can_cancel: bool) {
+        let buttons = if can_cancel { gtk::ButtonsType::OkCancel } else { gtk::ButtonsType::Ok };
+        let dialog = gtk::MessageDialog::new(Some(&self.window), gtk::DialogFlags::MODAL, message_type, buttons, title);
+        dialog.set_secondary_text(Some(message));
+        let res_tx = self.res_tx.clone();
+        dialog.connect_response(move |dialog, response| {
+            dialog.close();
+            let _ = res_tx.send(!can_cancel || response == gtk::ResponseType::Ok);
+        });
+        dialog.present();
+    }
+    fn handle(&self, request: Request, main_loop: &glib::MainLoop) {
+        match request {
+            Request::SetProgress { task, subtask, progress } => {
+                if let Some(progress) = progress {
+                    self.bar.set_fraction(progress.clamp(0.0, 1.0) as f64);
+                }
+                self.determinate.set(progress.is_some());
+                if task != self.tasklabel.text() {
+                    self.tasklabel.set_text(&task);
+                }
+                if subtask != self.subtasklabel.text() {
+                    self.subtasklabel.set_text(&subtask);
+                }
+            },
+            Request::SetTitle { title } => {
+                self.window.set_title(Some(&title));
+            },
+            Request::Message { title, message } => {
+                self.show_dialog(&title, &message, gtk::MessageType::Info, false);
+            },
+            Request::Warning { title, message, can_cancel } => {
+                self.show_dialog(&title, &message, gtk::MessageType::Warning, can_cancel);
+            },
+            Request::Error { title, message } => {
+                self.show_dialog(&title, &message, gtk::MessageType::Error, false);
+            },
+            Request::Quit => {
+                self.window.close();
+                main_loop.quit();
+            },
+        }
+    }
+}
+
+pub struct GtkGui {
+    req_tx: glib::Sender<Request>,
+    res_rx: mpsc::Receiver<bool>,
+}
+
+impl GtkGui {
+    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(_: Option<bool>, f: T) -> Result<ExitCode, T> {
+        if gtk::init().is_err() {
+            return Err(f)
+        }
+        let (req_tx, req_rx) = glib::MainContext::channel(glib::Priority::default());
+        let (res_tx, res_rx) = mpsc::channel();
+        let main_loop = glib::MainLoop::new(None, false);
+        let window = GuiWindow::new(res_tx);
+        {
+            let main_loop = main_loop.clone();
+            req_rx.attach(None, move |request| {
+                window.handle(request, &main_loop);
+                glib::Continue(true)
+            });
+        }
+        let worker = {
+            let req_tx = req_tx.clone();
+            std::thread::spawn(move || {
+                let ret = f(Rc::new(RefCell::new(GtkGui { req_tx: req_tx.clone(), res_rx })));
+                let _ = req_tx.send(Request::Quit);
+                ret
+            })
+        };
+        drop(req_tx);
+        main_loop.run();
+        Ok(worker.join().unwrap_or(ExitCode::FAILURE))
+    }
+}
+
+impl Gui for GtkGui {
+    fn set_progress(&mut self, task: &str, subtask: &str, progress: Option<f32>) {
+        let _ = self.req_tx.send(Request::SetProgress { task: task.to_string(), subtask: subtask.to_string(), progress });
+    }
+    fn set_title(&mut self, title: &str) {
+        let _ = self.req_tx.send(Request::SetTitle { title: title.to_string() });
+    }
+    fn do_message(&mut self, title: &str, message: &str) {
+        let _ = self.req_tx.send(Request::Message { title: title.to_string(), message: message.to_string() });
+        let _ = self.res_rx.recv();
+    }
+    fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
+        if assume_yes() { return true }
+        let _ = self.req_tx.send(Request::Warning { title: title.to_string(), message: message.to_string(), can_cancel });
+        self.res_rx.recv().unwrap_or(false)
+    }
+    fn do_error(&mut self, title: &str, message: &str) {
+        let _ = self.req_tx.send(Request::Error { title: title.to_string(), message: message.to_string() });
+        let _ = self.res_rx.recv();
+    }
+}
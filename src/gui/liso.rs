@@ -1,8 +1,10 @@
 use std::{
+    io::Write,
     mem::swap,
+    time::Instant,
 };
 
-use ::liso::{Color, InputOutput, Response, liso};
+use ::liso::{Color, InputOutput, Line, Response, Style, liso};
 
 use super::*;
 
@@ -14,12 +16,66 @@ pub struct LisoGui {
     last_subtask_output: String,
     last_progress_output: Option<(u16,u16)>,
     pause: bool,
+    /// How long to wait at a "press enter" prompt before assuming they did.
+    input_timeout: Option<Duration>,
+    /// Set by `set_title`. Goes in front of the title of every message,
+    /// warning, and error (see `heading`).
+    window_title: Option<String>,
 }
 
 /// True if we should pause after outputting a message or error, false if we
 /// should not.
 const DEFAULT_PAUSE: bool = cfg!(any(feature="force_default_pause",all(windows,not(unix))));
 
+/// Finds anything in the message that looks like a URL. Returns the byte
+/// range of each one.
+fn find_urls(message: &str) -> Vec<(usize, usize)> {
+    let mut ret = vec![];
+    let mut pos = 0;
+    while let Some(start) = ["http://", "https://"].iter().filter_map(|x| message[pos..].find(x)).min().map(|x| pos + x) {
+        let end = message[start..].find(char::is_whitespace).map(|x| start + x).unwrap_or(message.len());
+        // A URL at the end of a sentence doesn't include the period.
+        let end = start + message[start..end].trim_end_matches(&['.', ',', ';', ':', ')', '"'][..]).len();
+        ret.push((start, end));
+        pos = end;
+    }
+    ret
+}
+
+/// Underlines anything in the message that looks like a URL, so it stands
+/// out. Used when we can't emit hyperlinks (see `hyperlink`).
+fn linkify(message: &str) -> Line {
+    let mut line = Line::new();
+    let mut pos = 0;
+    for (start, end) in find_urls(message) {
+        line.add_text(&message[pos..start]);
+        line.set_style(Style::UNDERLINE);
+        line.add_text(&message[start..end]);
+        line.set_style(Style::PLAIN);
+        pos = end;
+    }
+    line.add_text(&message[pos..]);
+    line
+}
+
+/// Wraps anything in the message that looks like a URL in an OSC 8
+/// hyperlink escape sequence (and underlines it), so that terminals that
+/// support it make it clickable. Returns `None` if there are no URLs.
+fn hyperlink(message: &str) -> Option<String> {
+    let urls = find_urls(message);
+    if urls.is_empty() { return None }
+    let mut ret = String::with_capacity(message.len() * 2);
+    let mut pos = 0;
+    for (start, end) in urls {
+        let url = &message[start..end];
+        ret.push_str(&message[pos..start]);
+        ret.push_str(&format!("\x1b]8;;{}\x1b\\\x1b[4m{}\x1b[24m\x1b]8;;\x1b\\", url, url));
+        pos = end;
+    }
+    ret.push_str(&message[pos..]);
+    Some(ret)
+}
+
 #[derive(Clone,Copy,Debug,PartialEq,Eq)]
 enum Consume {
     /// Consume everything. Return nothing.
@@ -72,20 +128,22 @@ impl Gui for LisoGui {
         self.consume_liso(Consume::All);
     }
     fn do_message(&mut self, title: &str, message: &str) {
+        let title = &self.heading(title);
         if self.pause {
             let last_progress = self.take_progress();
             self.io.as_mut().unwrap().wrapln(liso!(+bold, fg=green, title));
-            self.io.as_mut().unwrap().wrapln(message);
+            self.output_links(message);
             self.consume_liso(Consume::EnterToContinue);
             self.restore_progress(last_progress);
         }
         else {
             self.io.as_mut().unwrap().wrapln(liso!(+bold, fg=green, title));
-            self.io.as_mut().unwrap().wrapln(message);
+            self.output_links(message);
         }
     }
     fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
-        if self.pause || can_cancel {
+        let title = &self.heading(title);
+        if (self.pause || can_cancel) && !assume_yes() {
             let last_progress = self.take_progress();
             self.io.as_mut().unwrap().wrapln(liso!(+bold, fg=yellow, title));
             self.io.as_mut().unwrap().wrapln(message);
@@ -100,25 +158,29 @@ impl Gui for LisoGui {
         }
     }
     fn do_error(&mut self, title: &str, message: &str) {
+        let title = &self.heading(title);
         if self.pause {
             let last_progress = self.take_progress();
             self.io.as_mut().unwrap().wrapln(liso!(+bold, fg=red, title));
-            self.io.as_mut().unwrap().wrapln(message);
+            self.output_links(message);
             self.consume_liso(Consume::EnterToContinue);
             self.restore_progress(last_progress);
         }
         else {
             self.io.as_mut().unwrap().wrapln(liso!(+bold, fg=red, title));
-            self.io.as_mut().unwrap().wrapln(message);
+            self.output_links(message);
         }
     }
+    fn set_title(&mut self, title: &str) {
+        self.window_title = if title.is_empty() { None } else { Some(title.to_string()) };
+    }
     fn verbose(&mut self, message: &str) {
         self.io.as_mut().unwrap().wrapln(liso!(dim, fg=cyan, message));
     }
 }
 
 impl LisoGui {
-    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(pause: Option<bool>, f: T) -> Result<ExitCode, T> {
+    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(pause: Option<bool>, input_timeout: Option<Duration>, f: T) -> Result<ExitCode, T> {
         let io = InputOutput::new();
         io.prompt("", false, true);
         Ok(f(Rc::new(RefCell::new(LisoGui {
@@ -131,8 +193,43 @@ impl LisoGui {
                     false
                 } else { DEFAULT_PAUSE }
             }),
+            input_timeout,
+            window_title: None,
         }))))
     }
+    /// Outputs a message body, making any URLs in it into hyperlinks.
+    ///
+    /// Liso escapes control characters in everything it outputs, so when
+    /// we're on a terminal, we suspend it and write the hyperlink escape
+    /// sequences ourselves. Otherwise, they would only be noise, so we just
+    /// underline the URLs.
+    fn output_links(&mut self, message: &str) {
+        match hyperlink(message) {
+            Some(text) if atty::is(atty::Stream::Stdout) => {
+                let mut text = Some(text);
+                self.io.as_mut().unwrap().suspend_and_run(move || {
+                    if let Some(text) = text.take() {
+                        let mut stdout = std::io::stdout().lock();
+                        let _ = writeln!(stdout, "{}", text);
+                        let _ = stdout.flush();
+                    }
+                });
+            },
+            _ => self.io.as_mut().unwrap().wrapln(linkify(message)),
+        }
+    }
+    /// Puts the window title, if any, in front of a message title.
+    ///
+    /// This is the only place it goes. It was first meant to prefix all of
+    /// our output, but repeating it on every line of a message, right under
+    /// a title that already has it, is just noise, and the progress display
+    /// and verbose output are clear enough about whose they are.
+    fn heading(&self, title: &str) -> String {
+        match self.window_title.as_ref() {
+            Some(window_title) => format!("{}: {}", window_title, title),
+            None => title.to_string(),
+        }
+    }
     fn take_progress(&mut self) -> (String, String, Option<(u16,u16)>) {
         let (mut last_task_output, mut last_subtask_output, last_progress_output)
         = (String::new(), String::new(), self.last_progress_output.take());
@@ -148,6 +245,8 @@ impl LisoGui {
                 while let Some(response) = self.io.as_mut().unwrap().try_read() {
                     match response {
                         Response::Dead => std::process::exit(1),
+                        // control-C or control-D while we're working
+                        Response::Quit | Response::Finish => request_cancel(),
                         _ => (),
                     }
                 }
@@ -161,10 +260,21 @@ impl LisoGui {
                 self.io.as_mut().unwrap().prompt(liso!(dim, prompt_text, -dim), true, true);
                 // workaround for blocking_recv being disallowed in an async context
                 let mut io = self.io.take().unwrap();
+                let deadline = self.input_timeout.map(|x| Instant::now() + x);
                 let result = std::thread::spawn(move || {
                     let ret;
                     loop {
-                        let response = io.read_blocking();
+                        let response = match deadline {
+                            None => io.read_blocking(),
+                            Some(deadline) => match io.read_deadline(deadline) {
+                                Some(x) => x,
+                                None => {
+                                    tracing::info!("Input timeout; assuming OK");
+                                    ret = Some(String::new());
+                                    break;
+                                },
+                            },
+                        };
                         match response {
                             Response::Input(x) => {
                                 ret = Some(x);
@@ -214,4 +324,17 @@ impl Drop for LisoGui {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyperlinks_urls() {
+        assert_eq!(hyperlink("no links here"), None);
+        assert_eq!(
+            hyperlink("See https://example.com/x. Or don't.").unwrap(),
+            "See \x1b]8;;https://example.com/x\x1b\\\x1b[4mhttps://example.com/x\x1b[24m\x1b]8;;\x1b\\. Or don't."
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use super::*;
+
+/// Something a `MockGui` was asked to do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockGuiEvent {
+    Progress { task: String, subtask: String, progress: Option<f32> },
+    Message { title: String, message: String },
+    Warning { title: String, message: String, can_cancel: bool },
+    Error { title: String, message: String },
+    Verbose(String),
+}
+
+/// A GUI for tests. Displays nothing, and just remembers what it was asked to
+/// do.
+pub struct MockGui {
+    /// Whether to act as though Cancel was pressed on warnings that have a
+    /// Cancel button.
+    should_cancel: bool,
+    pub events: Vec<MockGuiEvent>,
+}
+
+impl MockGui {
+    pub fn new(should_cancel: bool) -> MockGui {
+        MockGui { should_cancel, events: Vec::new() }
+    }
+}
+
+impl Gui for MockGui {
+    fn set_progress(&mut self, task: &str, subtask: &str, progress: Option<f32>) {
+        self.events.push(MockGuiEvent::Progress { task: task.to_string(), subtask: subtask.to_string(), progress });
+    }
+    fn do_message(&mut self, title: &str, message: &str) {
+        self.events.push(MockGuiEvent::Message { title: title.to_string(), message: message.to_string() });
+    }
+    fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
+        self.events.push(MockGuiEvent::Warning { title: title.to_string(), message: message.to_string(), can_cancel });
+        !(can_cancel && self.should_cancel)
+    }
+    fn do_error(&mut self, title: &str, message: &str) {
+        self.events.push(MockGuiEvent::Error { title: title.to_string(), message: message.to_string() });
+    }
+    fn verbose(&mut self, message: &str) {
+        self.events.push(MockGuiEvent::Verbose(message.to_string()));
+    }
+}
@@ -2,6 +2,8 @@ use std::{
     cell::RefCell,
     process::ExitCode,
     rc::Rc,
+    time::Duration,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 mod batch;
@@ -9,6 +11,14 @@ mod batch;
 mod liso;
 #[cfg(target_os="macos")]
 mod cocoa;
+#[cfg(feature="gui_gtk")]
+mod gtk;
+#[cfg(all(windows, feature="gui_winapi"))]
+mod winapi;
+#[cfg(feature="gui_dbus_notify")]
+mod dbus_notify;
+#[cfg(any(test, fuzzing))]
+pub mod mock;
 
 /// A graphical front end for Tupdate.
 pub trait Gui: Send {
@@ -26,21 +36,71 @@ pub trait Gui: Send {
     /// Display an error, with an OK button. Return after display. Title not
     /// displayed on all GUIs.
     fn do_error(&mut self, title: &str, message: &str);
-    /// Do "verbose output" to stderr or stdout or system log or etc.
+    /// Display an error, along with a short code that the user can pass on
+    /// when asking for help.
+    fn do_error_coded(&mut self, title: &str, message: &str, code: &str) {
+        self.do_error(title, &format!("{}\n(Error code: {})", message, code))
+    }
+    /// Ask a yes/no question that isn't a warning. Returns true if the answer
+    /// was yes. Title not displayed on all GUIs.
+    fn do_question(&mut self, title: &str, question: &str) -> bool {
+        self.do_warning(title, question, true)
+    }
+    /// Change the title of the progress window, if there is one.
+    fn set_title(&mut self, _title: &str) {}
+    /// Do "verbose output" to stderr or stdout or system log or etc. Receives
+    /// our log messages, when `--verbose` is given.
     fn verbose(&mut self, message: &str) {
         eprintln!("{}", message);
     }
 }
 
+/// Set when the user has asked (e.g. with control-C) to cancel the update.
+static CANCEL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Called by a GUI when the user asks to cancel the update.
+pub fn request_cancel() {
+    CANCEL_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if the user has asked to cancel the update.
+pub fn cancel_requested() -> bool {
+    CANCEL_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Set when `--yes` was given.
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if every warning should be treated as though OK was pressed,
+/// without asking.
+pub fn assume_yes() -> bool {
+    ASSUME_YES.load(Ordering::Relaxed)
+}
+
 /// Tries to make a new GUI and use it to run the given function. Returns an
 /// `ExitCode`.
-pub fn run_gui<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(mut target_gui: Option<String>, pause: Option<bool>, f: T) -> ExitCode {
+pub fn run_gui<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(mut target_gui: Option<String>, pause: Option<bool>, yes: bool, input_timeout: Option<Duration>, json: bool, batch_errors_to_stdout: bool, f: T) -> ExitCode {
+    let pause = if yes {
+        ASSUME_YES.store(true, Ordering::Relaxed);
+        Some(false)
+    } else { pause };
+    #[cfg(not(feature="gui_liso"))]
+    let _ = input_timeout;
     if target_gui.as_ref().map(String::as_str) == Some("help") {
         println!("Available GUIs:");
-        println!("    batch: No progress information. Outputs all messages directly to stdout. Assumes \"OK\" on all prompts.");
+        println!("    batch: No progress information. Outputs messages to stdout, and warnings, questions, and errors to stderr (unless --batch-errors-to-stdout). Assumes \"OK\" on all prompts. With --json, outputs everything (including progress) as newline-delimited JSON.");
         if cfg!(target_os="macos") {
             println!("    cocoa: Full Macintosh GUI.");
         }
+        if cfg!(feature="gui_gtk") {
+            println!("    gtk: Full GTK GUI. (Used by default on Linux if running in a graphical session and not in an interactive terminal.)");
+        }
+        if cfg!(all(windows, feature="gui_winapi")) {
+            println!("    winapi: Full Windows GUI. (Used by default on Windows if not in an interactive terminal.)");
+        }
+        if cfg!(feature="gui_dbus_notify") {
+            println!("    dbus_notify: Desktop notifications, over D-Bus. (Used by default if a D-Bus session bus is available, but there is no graphical session or interactive terminal.)");
+        }
         if cfg!(feature="gui_liso") {
             println!("    liso: Interactive terminal experience. Pipe friendly. (Used by default if all three standard file descriptors are for an interactive terminal.)");
         }
@@ -54,11 +114,17 @@ pub fn run_gui<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'stat
     }
     if let Some(target_gui) = target_gui {
         match target_gui.as_str() {
-            "batch" => return batch::BatchGui::go(pause, f).unwrap_or(ExitCode::FAILURE),
+            "batch" => return batch::BatchGui::go(pause, json, batch_errors_to_stdout, f).unwrap_or(ExitCode::FAILURE),
             #[cfg(target_os="macos")]
             "cocoa" => return cocoa::CocoaGui::go(pause, f).unwrap_or(ExitCode::FAILURE),
+            #[cfg(feature="gui_gtk")]
+            "gtk" => return gtk::GtkGui::go(pause, f).unwrap_or(ExitCode::FAILURE),
+            #[cfg(all(windows, feature="gui_winapi"))]
+            "winapi" => return winapi::WinapiGui::go(pause, f).unwrap_or(ExitCode::FAILURE),
+            #[cfg(feature="gui_dbus_notify")]
+            "dbus_notify" => return dbus_notify::DbusNotifyGui::go(pause, f).unwrap_or(ExitCode::FAILURE),
             #[cfg(feature="gui_liso")]
-            "liso" => return liso::LisoGui::go(pause, f).unwrap_or(ExitCode::FAILURE),
+            "liso" => return liso::LisoGui::go(pause, input_timeout, f).unwrap_or(ExitCode::FAILURE),
             _ => {
                 eprintln!("The GUI type you requested is unknown or unavailable. Try \"--gui help\".");
                 return ExitCode::FAILURE
@@ -70,13 +136,31 @@ pub fn run_gui<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'stat
         Ok(x) => return x,
         Err(x) => x,
     };
-    // Wayland or X GUIs would go here
+    #[cfg(feature="gui_gtk")]
+    let f = if std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        match gtk::GtkGui::go(pause, f) {
+            Ok(x) => return x,
+            Err(x) => x,
+        }
+    } else { f };
+    #[cfg(all(windows, feature="gui_winapi"))]
+    let f = match winapi::WinapiGui::go(pause, f) {
+        Ok(x) => return x,
+        Err(x) => x,
+    };
+    #[cfg(feature="gui_dbus_notify")]
+    let f = if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some() && std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+        match dbus_notify::DbusNotifyGui::go(pause, f) {
+            Ok(x) => return x,
+            Err(x) => x,
+        }
+    } else { f };
     #[cfg(feature="gui_liso")]
-    let f = match liso::LisoGui::go(pause, f) {
+    let f = match liso::LisoGui::go(pause, input_timeout, f) {
         Ok(x) => return x,
         Err(x) => x,
     };
-    let _f = match batch::BatchGui::go(pause, f) {
+    let _f = match batch::BatchGui::go(pause, json, batch_errors_to_stdout, f) {
         Ok(x) => return x,
         Err(x) => x,
     };
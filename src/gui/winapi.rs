@@ -0,0 +1,237 @@
+use super::*;
+
+use std::{
+    process::ExitCode,
+    sync::mpsc,
+};
+
+use windows_sys::Win32::{
+    Foundation::{HWND, LPARAM, LRESULT, RECT, WPARAM},
+    Graphics::Gdi::{COLOR_WINDOW, HBRUSH},
+    System::LibraryLoader::GetModuleHandleW,
+    UI::Controls::{
+        InitCommonControlsEx, INITCOMMONCONTROLSEX, ICC_PROGRESS_CLASS,
+        PBM_SETMARQUEE, PBM_SETPOS, PBM_SETRANGE32, PBS_MARQUEE, PROGRESS_CLASSW,
+    },
+    UI::WindowsAndMessaging::*,
+};
+
+const WINDOW_TITLE: &str = "Tejat Updater";
+const WINDOW_CLASS: &str = "TUpdateWindow";
+const CLIENT_WIDTH: i32 = 512;
+const TOP_GAP: i32 = 16;
+const BAR_GAP: i32 = 12;
+const HGAP: i32 = 24;
+const LABEL_HEIGHT: i32 = 20;
+const BAR_HEIGHT: i32 = 20;
+/// The progress bar's range is 0 to this.
+const BAR_RANGE: f32 = 1000.0;
+/// Carries a boxed `Request` in its `LPARAM`.
+const WM_REQUEST: u32 = WM_APP + 1;
+
+#[derive(Debug)]
+enum Request {
+    SetProgress { task: String, subtask: String, progress: Option<f32> },
+    SetTitle { title: String },
+    Message { title: String, message: String },
+    Warning { title: String, message: String, can_cancel: bool },
+    Error { title: String, message: String },
+    Quit,
+}
+
+/// Lives on the main thread.
+struct GuiWindow {
+    tasklabel: HWND,
+    subtasklabel: HWND,
+    bar: HWND,
+    determinate: bool,
+    res_tx: mpsc::Sender<bool>,
+}
+
+thread_local! {
+    static WINDOW: RefCell<Option<GuiWindow>> = RefCell::new(None);
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+unsafe fn set_bar_determinate(bar: HWND, determinate: bool) {
+    let style = GetWindowLongPtrW(bar, GWL_STYLE);
+    if determinate {
+        SendMessageW(bar, PBM_SETMARQUEE, 0, 0);
+        SetWindowLongPtrW(bar, GWL_STYLE, style & !(PBS_MARQUEE as isize));
+    }
+    else {
+        SetWindowLongPtrW(bar, GWL_STYLE, style | PBS_MARQUEE as isize);
+        SendMessageW(bar, PBM_SETMARQUEE, 1, 30);
+    }
+}
+
+unsafe fn show_dialog(hwnd: HWND, title: &str, message: &str, style: MESSAGEBOX_STYLE) -> bool {
+    let title = wide(title);
+    let message = wide(message);
+    MessageBoxW(hwnd, message.as_ptr(), title.as_ptr(), style) == IDOK
+}
+
+unsafe fn handle(hwnd: HWND, request: Request) {
+    let dialog = match request {
+        Request::SetProgress { task, subtask, progress } => {
+            WINDOW.with(|window| {
+                let mut window = window.borrow_mut();
+                let window = window.as_mut().unwrap();
+                if progress.is_some() != window.determinate {
+                    set_bar_determinate(window.bar, progress.is_some());
+                    window.determinate = progress.is_some();
+                }
+                if let Some(progress) = progress {
+                    SendMessageW(window.bar, PBM_SETPOS, (progress.clamp(0.0, 1.0) * BAR_RANGE) as WPARAM, 0);
+                }
+                SetWindowTextW(window.tasklabel, wide(&task).as_ptr());
+                SetWindowTextW(window.subtasklabel, wide(&subtask).as_ptr());
+            });
+            None
+        },
+        Request::SetTitle { title } => {
+            SetWindowTextW(hwnd, wide(&title).as_ptr());
+            None
+        },
+        Request::Message { title, message } => Some((title, message, MB_OK | MB_ICONINFORMATION, false)),
+        Request::Warning { title, message, can_cancel } => Some((title, message, if can_cancel { MB_OKCANCEL } else { MB_OK } | MB_ICONWARNING, can_cancel)),
+        Request::Error { title, message } => Some((title, message, MB_OK | MB_ICONERROR, false)),
+        Request::Quit => {
+            DestroyWindow(hwnd);
+            None
+        },
+    };
+    if let Some((title, message, style, can_cancel)) = dialog {
+        // Don't hold the borrow while the dialog's own message loop runs.
+        let res_tx = WINDOW.with(|window| window.borrow().as_ref().unwrap().res_tx.clone());
+        let ok = show_dialog(hwnd, &title, &message, style);
+        let _ = res_tx.send(ok || !can_cancel);
+    }
+}
+
+unsafe extern "system" fn window_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match msg {
+        WM_REQUEST => {
+            let request = Box::from_raw(lparam as *mut Request);
+            handle(hwnd, *request);
+            0
+        },
+        // Don't let the window be closed out from under the update.
+        WM_CLOSE => 0,
+        WM_DESTROY => {
+            PostQuitMessage(0);
+            0
+        },
+        _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+}
+
+/// Creates the progress window, and its controls. Returns the window, or
+/// `None` if something went wrong.
+unsafe fn create_window(res_tx: mpsc::Sender<bool>) -> Option<HWND> {
+    let instance = GetModuleHandleW(std::ptr::null());
+    let controls = INITCOMMONCONTROLSEX {
+        dwSize: std::mem::size_of::<INITCOMMONCONTROLSEX>() as u32,
+        dwICC: ICC_PROGRESS_CLASS,
+    };
+    InitCommonControlsEx(&controls);
+    let class_name = wide(WINDOW_CLASS);
+    let class = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(window_proc),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: instance,
+        hIcon: 0,
+        hCursor: LoadCursorW(0, IDC_ARROW),
+        hbrBackground: (COLOR_WINDOW + 1) as HBRUSH,
+        lpszMenuName: std::ptr::null(),
+        lpszClassName: class_name.as_ptr(),
+    };
+    if RegisterClassW(&class) == 0 { return None }
+    let style = WS_OVERLAPPED | WS_CAPTION | WS_SYSMENU | WS_MINIMIZEBOX;
+    let client_height = TOP_GAP + LABEL_HEIGHT + BAR_GAP + BAR_HEIGHT + BAR_GAP;
+    let mut rect = RECT { left: 0, top: 0, right: CLIENT_WIDTH, bottom: client_height };
+    AdjustWindowRect(&mut rect, style, 0);
+    let title = wide(WINDOW_TITLE);
+    let hwnd = CreateWindowExW(0, class_name.as_ptr(), title.as_ptr(), style, CW_USEDEFAULT, CW_USEDEFAULT, rect.right - rect.left, rect.bottom - rect.top, 0, 0, instance, std::ptr::null());
+    if hwnd == 0 { return None }
+    let static_class = wide("STATIC");
+    let initializing = wide("Initializing...");
+    let empty = wide("");
+    let tasklabel = CreateWindowExW(0, static_class.as_ptr(), initializing.as_ptr(), WS_CHILD | WS_VISIBLE | SS_LEFT, HGAP, TOP_GAP, CLIENT_WIDTH - HGAP * 2, LABEL_HEIGHT, hwnd, 0, instance, std::ptr::null());
+    let subtasklabel = CreateWindowExW(0, static_class.as_ptr(), empty.as_ptr(), WS_CHILD | WS_VISIBLE | SS_RIGHT, HGAP, TOP_GAP, CLIENT_WIDTH - HGAP * 2, LABEL_HEIGHT, hwnd, 0, instance, std::ptr::null());
+    let bar = CreateWindowExW(0, PROGRESS_CLASSW, empty.as_ptr(), WS_CHILD | WS_VISIBLE | PBS_MARQUEE, HGAP, TOP_GAP + LABEL_HEIGHT + BAR_GAP, CLIENT_WIDTH - HGAP * 2, BAR_HEIGHT, hwnd, 0, instance, std::ptr::null());
+    if tasklabel == 0 || subtasklabel == 0 || bar == 0 {
+        DestroyWindow(hwnd);
+        return None
+    }
+    SendMessageW(bar, PBM_SETRANGE32, 0, BAR_RANGE as LPARAM);
+    SendMessageW(bar, PBM_SETMARQUEE, 1, 30);
+    WINDOW.with(|window| {
+        *window.borrow_mut() = Some(GuiWindow { tasklabel, subtasklabel, bar, determinate: false, res_tx });
+    });
+    ShowWindow(hwnd, SW_SHOW);
+    Some(hwnd)
+}
+
+pub struct WinapiGui {
+    hwnd: HWND,
+    res_rx: mpsc::Receiver<bool>,
+}
+
+impl WinapiGui {
+    pub fn go<T: FnOnce(Rc<RefCell<dyn Gui>>) -> ExitCode + Send + Sync + 'static>(_: Option<bool>, f: T) -> Result<ExitCode, T> {
+        let (res_tx, res_rx) = mpsc::channel();
+        let hwnd = match unsafe { create_window(res_tx) } {
+            Some(x) => x,
+            None => return Err(f),
+        };
+        let worker = std::thread::spawn(move || {
+            let gui = Rc::new(RefCell::new(WinapiGui { hwnd, res_rx }));
+            let ret = f(gui.clone());
+            gui.borrow().post(Request::Quit);
+            ret
+        });
+        unsafe {
+            let mut msg: MSG = std::mem::zeroed();
+            while GetMessageW(&mut msg, 0, 0, 0) > 0 {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+        Ok(worker.join().unwrap_or(ExitCode::FAILURE))
+    }
+    fn post(&self, request: Request) {
+        let request = Box::into_raw(Box::new(request));
+        if unsafe { PostMessageW(self.hwnd, WM_REQUEST, 0, request as LPARAM) } == 0 {
+            // Never delivered, so it's still ours.
+            drop(unsafe { Box::from_raw(request) });
+        }
+    }
+}
+
+impl Gui for WinapiGui {
+    fn set_progress(&mut self, task: &str, subtask: &str, progress: Option<f32>) {
+        self.post(Request::SetProgress { task: task.to_string(), subtask: subtask.to_string(), progress });
+    }
+    fn set_title(&mut self, title: &str) {
+        self.post(Request::SetTitle { title: title.to_string() });
+    }
+    fn do_message(&mut self, title: &str, message: &str) {
+        self.post(Request::Message { title: title.to_string(), message: message.to_string() });
+        let _ = self.res_rx.recv();
+    }
+    fn do_warning(&mut self, title: &str, message: &str, can_cancel: bool) -> bool {
+        if assume_yes() { return true }
+        self.post(Request::Warning { title: title.to_string(), message: message.to_string(), can_cancel });
+        self.res_rx.recv().unwrap_or(false)
+    }
+    fn do_error(&mut self, title: &str, message: &str) {
+        self.post(Request::Error { title: title.to_string(), message: message.to_string() });
+        let _ = self.res_rx.recv();
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    fmt::Write as _,
+    sync::Mutex,
+};
+
+use tracing::{field::{Field, Visit}, Event, Level, Subscriber};
+use tracing_subscriber::{
+    filter::Targets,
+    layer::{Context, Layer, SubscriberExt},
+    util::SubscriberInitExt,
+};
+
+use super::*;
+
+thread_local! {
+    /// The GUI that log messages on this thread should go to, if any.
+    static LOG_GUI: RefCell<Option<Rc<RefCell<dyn Gui>>>> = RefCell::new(None);
+}
+
+/// Messages that couldn't be handed to the GUI yet, either because they came
+/// from another thread (e.g. while examining local files) or because the GUI
+/// was busy at the time.
+static PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Pulls the message (and any other fields) out of an event.
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        }
+        else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Forwards events to `Gui::verbose`.
+struct GuiLayer;
+
+impl<S: Subscriber> Layer<S> for GuiLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = String::new();
+        event.record(&mut MessageVisitor(&mut message));
+        PENDING.lock().unwrap().push(message);
+        flush();
+    }
+}
+
+/// Hands any log messages that are waiting for the GUI to it, if this is the
+/// GUI's thread and the GUI isn't busy.
+pub fn flush() {
+    LOG_GUI.with(|gui| {
+        let gui = gui.borrow();
+        let gui = match gui.as_ref() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut gui = match gui.try_borrow_mut() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+        for message in pending.iter() {
+            gui.verbose(message);
+        }
+    });
+}
+
+/// Sets up logging. Our own `DEBUG` and `INFO` (and more severe) events go to
+/// `log_file` if one is given, and to the GUI's `verbose` method (which, by
+/// default, writes to stderr) if `verbose` is true.
+pub fn init_logging(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, log_file: Option<&Path>) -> Result<(), ()> {
+    let file_layer = match log_file {
+        None => None,
+        Some(path) => match OpenOptions::new().append(true).create(true).open(path) {
+            Ok(x) => Some(tracing_subscriber::fmt::layer().with_writer(Mutex::new(x))),
+            Err(x) => {
+                gui.borrow_mut().do_error("Couldn't open log file", &format!("Couldn't open the log file. The path was:\n{:?}\nand the error was:\n{}", path, x));
+                return Err(())
+            },
+        },
+    };
+    let gui_layer = if verbose {
+        LOG_GUI.with(|x| *x.borrow_mut() = Some(gui.clone()));
+        Some(GuiLayer)
+    } else { None };
+    // Our dependencies have plenty to say, too. We don't want to hear it.
+    let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::DEBUG);
+    let _ = tracing_subscriber::registry()
+        .with(file_layer)
+        .with(gui_layer)
+        .with(targets)
+        .try_init();
+    Ok(())
+}
+
+/// Hands over anything still pending, then lets go of the GUI, so that it can
+/// be dropped (and clean up after itself) as usual.
+pub fn finish_logging() {
+    flush();
+    LOG_GUI.with(|x| *x.borrow_mut() = None);
+}
@@ -1,19 +1,26 @@
 use std::{
     cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque, hash_map::Entry as HashMapEntry},
     env::current_exe,
     error::Error,
-    fs::File,
-    io::{Read, BufRead, BufReader, ErrorKind, Write},
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{Read, ErrorKind, Write},
     process::ExitCode,
     path::{Path, PathBuf},
     rc::Rc,
-    sync::Mutex,
-    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
-    time::Instant,
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+    time::{Duration, Instant, SystemTime},
 };
 
+use base64::Engine;
 use clap::Parser;
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use fs2::FileExt;
+use futures_util::StreamExt;
 use rayon::prelude::*;
+use serde::Deserialize;
+use tracing::{debug, info};
 use url::Url;
 use wax::Glob;
 
@@ -21,155 +28,838 @@ mod gui;
 use gui::*;
 
 mod update_finder;
-use update_finder::find_updates;
+use update_finder::{find_updates, FindUpdatesError};
 
 mod patience;
 use patience::Patience;
 
+mod backup;
+use backup::Backup;
+
+mod proxy;
+use proxy::configure_proxy;
+
+mod logging;
+use logging::{init_logging, finish_logging};
+
+mod catalog;
+use catalog::*;
+
+mod catalog_cache;
+use catalog_cache::CatalogCache;
+
 const CONFIG_FILE_PATH: &str = "tupdate.conf";
+/// The directory, inside the platform's user config directory, that we look
+/// for `CONFIG_FILE_PATH` in.
+const CONFIG_DIR_NAME: &str = if cfg!(any(windows, target_os="macos")) { "TUpdate" } else { "tupdate" };
+/// Error codes shown alongside network errors, which are otherwise hard for
+/// users to describe when they ask for help.
+const ERR_CATALOG_STATUS: &str = "TUP-NET-001";
+const ERR_CATALOG_TRANSFER: &str = "TUP-NET-002";
+const ERR_INDEX_TRANSFER: &str = "TUP-NET-003";
+const ERR_FILE_STATUS: &str = "TUP-NET-004";
+const ERR_FILE_TRANSFER: &str = "TUP-NET-005";
+const ERR_FILE_LENGTH: &str = "TUP-NET-006";
+const ERR_FILE_OVERRUN: &str = "TUP-NET-007";
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_CONNECT_TIMEOUT: f64 = 30.0;
+const DEFAULT_CHANNEL: &str = "stable";
+const DEFAULT_USER_AGENT: &str = concat!("TUpdate/", env!("CARGO_PKG_VERSION"));
+
+/// Parses a number of bytes like `500k` or `2M`.
+fn parse_byte_count(s: &str) -> Option<u64> {
+    let (number, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len()-1], 1000.0),
+        Some('M') => (&s[..s.len()-1], 1000000.0),
+        Some('G') => (&s[..s.len()-1], 1000000000.0),
+        _ => (s, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(x) if x > 0.0 && (x * multiplier) >= 1.0 => Some((x * multiplier) as u64),
+        _ => None,
+    }
+}
+
+/// Parses a byte rate like `500k` or `2M`.
+fn parse_rate(s: &str) -> Result<u64, String> {
+    parse_byte_count(s).ok_or_else(|| "expected a positive number of bytes per second, optionally followed by k, M, or G".to_string())
+}
+
+/// Parses a byte limit like `500M` or `2G`.
+fn parse_byte_limit(s: &str) -> Result<u64, String> {
+    parse_byte_count(s).ok_or_else(|| "expected a positive number of bytes, optionally followed by k, M, or G".to_string())
+}
 
-fn is_fishy_path(target: &str) -> bool {
-    target.starts_with(".") || target.starts_with("/") || target.starts_with("\\") || target.find("/.").is_some() || target.find("\\.").is_some()
+/// Parses a number of seconds, which must be finite and not negative.
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(x),
+        _ => Err("expected a number of seconds, zero or more".to_string()),
+    }
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Invocation {
     /// Which GUI to use. Use `--gui help` for more information.
-    #[arg(short, long)]
+    #[arg(short, long, env = "TUPDATE_GUI")]
     gui: Option<String>,
     /// Whether to output extra status information about what we're doing and
     /// why.
-    #[arg(short, long)]
+    #[arg(short, long, env = "TUPDATE_VERBOSE")]
     verbose: bool,
+    /// Also write the extra status information to this file, whether or not
+    /// `--verbose` is given.
+    #[arg(long)]
+    log_file: Option<PathBuf>,
     #[arg(short, long)]
     /// Pause and wait for a response after every dialog, if supported by the
     /// selected GUI. Default depends on the GUI and the platform.
     #[arg(short, long)]
     pause: Option<bool>,
+    /// Assume "OK" for every warning, instead of asking. Also implies
+    /// `--pause false`.
+    #[arg(short, long)]
+    yes: bool,
+    /// If specified, when a GUI is waiting for the user to press a key, give
+    /// up after this many seconds and act as though they pressed Enter.
+    /// Currently only supported by the `liso` GUI.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    input_timeout: Option<f64>,
+    /// When using the batch GUI, output newline-delimited JSON objects instead
+    /// of human-readable text.
+    #[arg(long)]
+    json: bool,
+    /// When using the batch GUI, write warnings, questions, and errors to
+    /// stdout (as older versions did) instead of stderr.
+    #[arg(long)]
+    batch_errors_to_stdout: bool,
+    /// The maximum number of files to download at the same time.
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    /// The maximum number of catalogs to download at the same time.
+    #[arg(long, default_value_t = 8)]
+    catalog_jobs: usize,
+    /// The maximum number of Lua instructions the update index may run before
+    /// we give up on it, or 0 for no limit.
+    #[arg(long, default_value_t = 10000000)]
+    lua_limit: u64,
+    /// Install into this directory instead of the one the update index would
+    /// detect. The index sees it as `basedir_override`, and may not pick a
+    /// base directory outside of it.
+    #[arg(long, value_name = "PATH")]
+    basedir: Option<PathBuf>,
+    /// Which release channel to update to, e.g. `beta`. The update index sees
+    /// it as `channel`. [default: stable, or `channel` from the config file]
+    #[arg(long)]
+    channel: Option<String>,
+    /// Don't try to pick up where an interrupted download left off; always
+    /// download files from the beginning.
+    #[arg(long)]
+    no_resume: bool,
+    /// Always download catalogs in full, instead of reusing our copy from
+    /// last time if the server says it hasn't changed.
+    #[arg(long)]
+    no_cache: bool,
+    /// Before replacing or deleting any file, copy it into this directory,
+    /// so that a failed update can be rolled back.
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+    /// Report what would be downloaded and deleted, without actually changing
+    /// anything. Exits with status 1 if there are changes to be made.
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Only check whether the local files match the catalog, without
+    /// downloading anything. Exits with status 1 if any don't, or with the
+    /// usual status for whatever went wrong (e.g. 2 if a catalog couldn't be
+    /// fetched) if they couldn't be checked.
+    #[arg(long)]
+    verify: bool,
+    /// Download every file in the catalog again, without checking whether
+    /// the local copy is already correct.
+    #[arg(long, visible_alias = "force", conflicts_with = "verify")]
+    repair: bool,
+    /// Only check whether any updates are available, without applying them.
+    /// Exits with status 1 if there are.
+    #[arg(long)]
+    check: bool,
+    /// Print every file in the catalogs, and its size in bytes, one per line
+    /// and separated by a tab, without examining or downloading anything.
+    #[arg(long, conflicts_with_all = ["verify", "check", "dry_run", "repair"])]
+    list: bool,
+    /// How many times to retry a request that failed in a way that might be
+    /// temporary. [default: 3, or `retries` from the config file]
+    #[arg(long)]
+    retries: Option<u32>,
+    /// How many seconds to wait before retrying a failed request. Doubles
+    /// with each successive retry.
+    #[arg(long, default_value_t = 2.0, value_parser = parse_seconds)]
+    retry_delay: f64,
+    /// How many seconds to wait for a connection to be established.
+    /// [default: 30, or `connect_timeout` from the config file]
+    #[arg(long, value_parser = parse_seconds)]
+    connect_timeout: Option<f64>,
+    /// How many seconds to wait for more data to arrive in the middle of a
+    /// download.
+    #[arg(long, default_value_t = 120.0, value_parser = parse_seconds)]
+    read_timeout: f64,
+    /// If specified, the maximum number of seconds any single request may
+    /// take, from start to finish.
+    #[arg(long, value_parser = parse_seconds)]
+    timeout: Option<f64>,
+    /// Send all requests through this proxy. If not specified, the
+    /// `HTTP_PROXY`, `HTTPS_PROXY`, and `NO_PROXY` environment variables are
+    /// honored.
+    #[arg(long)]
+    proxy: Option<String>,
+    /// Identify ourselves to the server with this, instead of
+    /// `TUpdate/<version>`. Overrides `USER_AGENT=` in the config file.
+    #[arg(long)]
+    user_agent: Option<String>,
+    /// Send this with every request, as `Authorization: Bearer <token>`.
+    /// Overrides `TOKEN=` and `TOKEN_FILE=` in the config file.
+    #[arg(long, value_name = "SECRET")]
+    token: Option<String>,
+    /// Allow catalogs to come from this host, as well as the one the update
+    /// index came from. May be given more than once.
+    #[arg(long, value_name = "HOSTNAME")]
+    allow_host: Vec<String>,
+    /// Trust the (PEM-encoded) root certificate in this file, in addition to
+    /// the usual ones. Overrides `CA_CERT=` in the config file.
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    /// Identify ourselves to the server with the (PEM-encoded) certificate in
+    /// this file. Requires `--client-key`.
+    #[arg(long, value_name = "PATH")]
+    client_cert: Option<PathBuf>,
+    /// The (PEM-encoded, PKCS#8) private key for `--client-cert`.
+    #[arg(long, value_name = "PATH")]
+    client_key: Option<PathBuf>,
+    /// Identify ourselves to the server with the certificate and key in this
+    /// PKCS#12 (`.pfx` or `.p12`) file, instead of `--client-cert` and
+    /// `--client-key`.
+    #[arg(long, value_name = "PATH")]
+    client_pfx: Option<PathBuf>,
+    /// The password for `--client-pfx`, if it has one.
+    #[arg(long, value_name = "SECRET")]
+    client_pfx_password: Option<String>,
+    /// Require every catalog to be signed by this (base64-encoded) Ed25519
+    /// public key. Overrides `PUBLIC_KEY=` in the config file.
+    #[arg(long, value_name = "BASE64")]
+    public_key: Option<String>,
+    /// The maximum total download rate, in bytes per second. May be followed
+    /// by k, M, or G (e.g. `500k` or `2M`). Overrides `max_rate` from the
+    /// config file.
+    #[arg(long, value_parser = parse_rate)]
+    max_rate: Option<u64>,
+    /// Refuse to download more than this many bytes in total. May be
+    /// followed by k, M, or G (e.g. `500M` or `2G`).
+    #[arg(long, value_name = "BYTES", value_parser = parse_byte_limit)]
+    max_total_download: Option<u64>,
+    /// The URL of the update index. Overrides the config file, but is itself
+    /// overridden by the `TUPDATE_URL` environment variable.
     target_url: Option<Url>,
 }
 
-#[derive(Debug)]
-struct Cat {
-    src_url: Url,
-    dst_path: PathBuf,
-    checksum: [u8; 32],
-    size: u64,
-    needs_download: bool,
-}
-
-impl Cat {
-    fn try_parse<'a>(bytes: &'a [u8], base_url: &Url, base_path: &Path) -> Result<(Cat, &'a [u8]), ()> {
-        let newline = bytes.iter().position(|x| *x == b'\n').ok_or(())?;
-        if newline == 0 { return Err(()) }
-        if newline > bytes.len() - 42 { return Err(()) }
-        let file_path = &bytes[..newline];
-        let file_path = std::str::from_utf8(file_path).map_err(|_| ())?;
-        let checksum = &bytes[newline+1 .. newline+33];
-        let size = u64::from_be_bytes(bytes[newline+33 .. newline+41].try_into().unwrap());
-        let xt = u16::from_be_bytes(bytes[newline+41 .. newline+43].try_into().unwrap());
-        let next = newline + 43 + xt as usize;
-        if next > bytes.len() { return Err(()) }
-        if is_fishy_path(file_path) { return Err(()) }
-        let src_url = base_url.join(file_path).map_err(|_| ())?;
-        Ok((Cat {
-            src_url,
-            dst_path: base_path.join(file_path),
-            checksum: checksum.try_into().unwrap(),
-            size,
-            needs_download: false,
-        }, &bytes[next..]))
-    }
-}
-
-fn try_load_url_from_file(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, path: &Path) -> Option<Url> {
-    if verbose {
-        gui.borrow_mut().verbose(&format!("Looking for update URL in: {:?}", path));
-    }
-    let f = match File::open(path) {
+/// Why an update failed. Each kind gets its own exit status, so that scripts
+/// can tell "try again later" apart from "something is broken". All of our
+/// exit statuses are:
+///
+/// - 0: success, or nothing needed doing
+/// - 1: any other failure (bad arguments, configuration, etc.); with
+///   `--check`, `--dry-run`, or `--verify`, there are changes to be made
+/// - 2: couldn't reach the server, or it wouldn't give us something
+/// - 3: a catalog was corrupted or invalid
+/// - 4: the update index (Lua) failed
+/// - 5: a downloaded file didn't match its catalog entry
+/// - 6: couldn't read or write a local file
+/// - 7: cancelled by the user
+/// - 8: the update would download more than `--max-total-download` allows
+///
+/// `--check`, `--dry-run`, and `--verify` all follow the same rule: 0 if
+/// everything is up to date, 1 if it isn't, and any of the above if we
+/// couldn't find out. (So `--verify` exits with 2 if a catalog can't be
+/// fetched, and `--dry-run` with 1 if there are changes, rather than the 2
+/// they were first specified with, which would be indistinguishable from a
+/// network error.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TupdateError {
+    NetworkError,
+    CatalogError,
+    LuaError,
+    VerificationError,
+    IoError,
+    Cancelled,
+    LimitExceeded,
+}
+
+impl From<TupdateError> for ExitCode {
+    fn from(error: TupdateError) -> ExitCode {
+        ExitCode::from(match error {
+            TupdateError::NetworkError => 2,
+            TupdateError::CatalogError => 3,
+            TupdateError::LuaError => 4,
+            TupdateError::VerificationError => 5,
+            TupdateError::IoError => 6,
+            TupdateError::Cancelled => 7,
+            TupdateError::LimitExceeded => 8,
+        })
+    }
+}
+
+/// Settings, other than the URL itself, that can come from `tupdate.conf`.
+#[derive(Debug, Default)]
+struct Config {
+    /// An extra root certificate to trust. (`CA_CERT=`)
+    ca_cert: Option<PathBuf>,
+    /// What to call the updater window, instead of "Tejat Updater". (`TITLE=`)
+    title: Option<String>,
+    /// Overridden by `--retries`. (TOML only)
+    retries: Option<u32>,
+    /// Overridden by `--connect-timeout`. (TOML only)
+    connect_timeout: Option<f64>,
+    /// Overridden by `--max-rate`. (TOML only)
+    max_rate: Option<u64>,
+    /// The key catalogs must be signed with, base64-encoded. (`PUBLIC_KEY=`)
+    public_key: Option<String>,
+    /// A shell command to run after an update that changed something.
+    /// (`POST_INSTALL=`)
+    post_install: Option<String>,
+    /// Overridden by `--channel`. (`CHANNEL=`)
+    channel: Option<String>,
+    /// Overridden by `--user-agent`. (`USER_AGENT=`)
+    user_agent: Option<String>,
+    /// Overridden by `--token`. (`TOKEN=`)
+    token: Option<String>,
+    /// A file containing the token, instead. (`TOKEN_FILE=`)
+    token_file: Option<PathBuf>,
+    /// The directory the config file was in, if there was one.
+    dir: Option<PathBuf>,
+}
+
+/// The TOML flavor of `tupdate.conf`. Everything goes in a `[tupdate]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TupdateConfig {
+    url: Option<String>,
+    mirrors: Vec<String>,
+    title: Option<String>,
+    retries: Option<u32>,
+    connect_timeout: Option<f64>,
+    /// Same syntax as `--max-rate`, e.g. `"1M"`.
+    max_rate: Option<String>,
+    ca_cert: Option<PathBuf>,
+    public_key: Option<String>,
+    post_install: Option<String>,
+    channel: Option<String>,
+    user_agent: Option<String>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfigFile {
+    tupdate: TupdateConfig,
+}
+
+/// Parses the original, line-by-line `KEY=VALUE` flavor of `tupdate.conf`.
+fn parse_plain_config(text: &str, config_dir: &Path) -> Option<(Vec<Url>, Config)> {
+    let mut urls = vec![];
+    let mut config = Config::default();
+    for line in text.lines() {
+        if let Some(slab) = line.strip_prefix("URL=") {
+            match Url::parse(slab) {
+                Ok(x) => urls.push(x),
+                Err(_) => {
+                    debug!("  File exists, but its URL= line does not contain a valid URL");
+                    return None
+                },
+            };
+        }
+        else if let Some(value) = line.strip_prefix("CA_CERT=") {
+            config.ca_cert = Some(config_dir.join(value));
+        }
+        else if let Some(value) = line.strip_prefix("TITLE=") {
+            config.title = Some(value.to_string());
+        }
+        else if let Some(value) = line.strip_prefix("PUBLIC_KEY=") {
+            config.public_key = Some(value.to_string());
+        }
+        else if let Some(value) = line.strip_prefix("POST_INSTALL=") {
+            config.post_install = Some(value.to_string());
+        }
+        else if let Some(value) = line.strip_prefix("CHANNEL=") {
+            config.channel = Some(value.to_string());
+        }
+        else if let Some(value) = line.strip_prefix("USER_AGENT=") {
+            config.user_agent = Some(value.to_string());
+        }
+        else if let Some(value) = line.strip_prefix("TOKEN=") {
+            config.token = Some(value.to_string());
+        }
+        else if let Some(value) = line.strip_prefix("TOKEN_FILE=") {
+            config.token_file = Some(config_dir.join(value));
+        }
+    }
+    Some((urls, config))
+}
+
+/// Parses the TOML flavor of `tupdate.conf`.
+fn parse_toml_config(text: &str, config_dir: &Path) -> Option<(Vec<Url>, Config)> {
+    let file: TomlConfigFile = match toml::from_str(text) {
         Ok(x) => x,
         Err(x) => {
-            if verbose {
-                gui.borrow_mut().verbose(&format!("  {}", x));
-            }
+            debug!("  File exists, but is not valid: {}", x);
             return None
         },
     };
-    let f = BufReader::new(f);
-    for line in f.lines() {
-        let line = line.expect("IO error while reading tupdate.conf!");
-        if line.starts_with("URL=") {
-            let slab = &line[4..];
-            let url = match Url::parse(slab) {
-                Ok(x) => x,
-                Err(_) => {
-                    if verbose {
-                        gui.borrow_mut().verbose(&format!("  File exists, but its URL= line does not contain a valid URL"));
-                    }
-                    return None
-                },
-            };
-            if verbose {
-                gui.borrow_mut().verbose(&format!("  {}", url));
-            }
-            return Some(url);
+    let file = file.tupdate;
+    let mut urls = vec![];
+    for url in file.url.iter().chain(file.mirrors.iter()) {
+        match Url::parse(url) {
+            Ok(x) => urls.push(x),
+            Err(_) => {
+                debug!("  File exists, but {:?} is not a valid URL", url);
+                return None
+            },
         }
     }
-    if verbose {
-        gui.borrow_mut().verbose(&format!("  File exists, but has no URL= line"));
+    let max_rate = match file.max_rate.as_deref().map(parse_rate) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(x)) => {
+            debug!("  File exists, but its max_rate is not valid: {}", x);
+            return None
+        },
+    };
+    if let Some(x) = file.connect_timeout {
+        if !x.is_finite() || x < 0.0 {
+            debug!("  File exists, but its connect_timeout is not valid");
+            return None
+        }
     }
-    None
+    Some((urls, Config {
+        ca_cert: file.ca_cert.map(|x| config_dir.join(x)),
+        title: file.title,
+        retries: file.retries,
+        connect_timeout: file.connect_timeout,
+        max_rate,
+        public_key: file.public_key,
+        post_install: file.post_install,
+        channel: file.channel,
+        user_agent: file.user_agent,
+        token: file.token,
+        token_file: file.token_file.map(|x| config_dir.join(x)),
+        dir: None,
+    }))
+}
+
+/// Returns every URL in the file, in order (the first is the primary, the
+/// rest are mirrors), along with the rest of the settings. The file may be
+/// TOML, in which case it must start with a `[tupdate]` table, or the
+/// original `KEY=VALUE` format.
+fn try_load_config(path: &Path) -> Option<(Vec<Url>, Config)> {
+    debug!("Looking for update URL in: {:?}", path);
+    let text = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(x) => {
+            debug!("  {}", x);
+            return None
+        },
+    };
+    // Relative paths in the file are relative to the file.
+    let config_dir = path.parent().unwrap_or(Path::new(""));
+    let (urls, mut config) = if text.trim_start().starts_with('[') {
+        parse_toml_config(&text, config_dir)?
+    }
+    else {
+        parse_plain_config(&text, config_dir)?
+    };
+    config.dir = Some(config_dir.to_path_buf());
+    if urls.is_empty() {
+        debug!("  File exists, but doesn't specify a URL");
+    }
+    for url in urls.iter() {
+        debug!("  {}", url);
+    }
+    Some((urls, config))
+}
+
+/// Finds `tupdate.conf`: next to the executable, in the user's config
+/// directory, or in the working directory. The first one with a URL in it
+/// wins; failing that, the first one there is at all.
+fn load_config() -> Option<(Vec<Url>, Config)> {
+    let mut candidates = vec![];
+    // Look next to the executable first.
+    if let Ok(mut exe_path) = current_exe() {
+        exe_path.pop();
+        exe_path.push(CONFIG_FILE_PATH);
+        candidates.push(exe_path);
+    }
+    // Look in the user's config directory.
+    if let Some(mut config_path) = dirs::config_dir() {
+        config_path.push(CONFIG_DIR_NAME);
+        config_path.push(CONFIG_FILE_PATH);
+        candidates.push(config_path);
+    }
+    // Look in the working directory.
+    candidates.push(PathBuf::from(CONFIG_FILE_PATH));
+    let mut fallback = None;
+    for path in candidates.iter() {
+        match try_load_config(path) {
+            Some((urls, config)) if !urls.is_empty() => return Some((urls, config)),
+            Some(x) if fallback.is_none() => fallback = Some(x),
+            _ => (),
+        }
+    }
+    fallback
 }
 
-fn find_target_url(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, mut target_url: Option<Url>) -> Result<Url, ()> {
-    if target_url == None {
-        // Look next to the executable first.
-        if let Ok(mut exe_path) = current_exe() {
-            exe_path.pop();
-            exe_path.push(CONFIG_FILE_PATH);
-            target_url = try_load_url_from_file(&gui, verbose, &exe_path);
+/// Returns the URLs to try, in order, along with any other settings we found.
+/// `TUPDATE_URL` or the command line may override the URL, but everything
+/// else in `tupdate.conf` (such as `PUBLIC_KEY=`) still applies.
+fn find_target_url(gui: &Rc<RefCell<dyn Gui>>, target_url: Option<Url>) -> Result<(Vec<Url>, Config), ()> {
+    let (file_urls, config) = load_config().unwrap_or_default();
+    let mut override_url = None;
+    // The environment trumps everything, so that scripts can redirect us
+    // without touching the command line or the config file.
+    if let Some(env_url) = std::env::var_os("TUPDATE_URL") {
+        match env_url.to_str().and_then(|x| Url::parse(x).ok()) {
+            Some(x) => {
+                info!("Using update URL from TUPDATE_URL: {}", x);
+                override_url = Some(x);
+            },
+            None => {
+                debug!("TUPDATE_URL is set, but does not contain a valid URL; ignoring it");
+            },
         }
     }
-    if target_url == None {
-        // Look in the working directory.
-        target_url = try_load_url_from_file(&gui, verbose, Path::new(CONFIG_FILE_PATH));
+    if override_url.is_none() {
+        if let Some(x) = target_url {
+            info!("Using update URL from the command line: {}", x);
+            override_url = Some(x);
+        }
     }
-    let target_url = match target_url {
-        None => {
-            gui.borrow_mut().do_error("No URL specified", &format!("Couldn't determine what URL to update from. Either pass one on the command line, or create a {:?}.", CONFIG_FILE_PATH));
+    let target_urls = match override_url {
+        Some(x) => vec![x],
+        None => file_urls,
+    };
+    if target_urls.is_empty() {
+        gui.borrow_mut().do_error("No URL specified", &format!("Couldn't determine what URL to update from. Either pass one on the command line, or create a {:?}.", CONFIG_FILE_PATH));
+        return Err(());
+    }
+    for target_url in target_urls.iter() {
+        match target_url.scheme() {
+            "http" | "https" => (), // okay
+            x => {
+                eprintln!("{:?} is not a supported URL scheme. Only http and https are supported.", x);
+                return Err(());
+            },
+        }
+    }
+    return Ok((target_urls, config))
+}
+
+/// Reads a PEM-encoded certificate, for `--ca-cert` or `CA_CERT=`.
+fn load_certificate(gui: &Rc<RefCell<dyn Gui>>, path: &Path) -> Result<reqwest::Certificate, ()> {
+    let pem = match std::fs::read(path) {
+        Ok(x) => x,
+        Err(x) => {
+            gui.borrow_mut().do_error("Invalid certificate", &format!("Couldn't read the CA certificate. The path was:\n{:?}\nand the error was:\n{}", path, x));
             return Err(());
         },
-        Some(x) => x,
     };
-    match target_url.scheme() {
-        "http" | "https" => (), // okay
-        x => {
-            eprintln!("{:?} is not a supported URL scheme. Only http and https are supported.", x);
+    match reqwest::Certificate::from_pem(&pem) {
+        Ok(x) => Ok(x),
+        Err(x) => {
+            gui.borrow_mut().do_error("Invalid certificate", &format!("Couldn't parse the CA certificate. The path was:\n{:?}\nand the error was:\n{}", path, x));
+            Err(())
+        },
+    }
+}
+
+/// Loads the client certificate from `--client-cert` and `--client-key`, or
+/// from `--client-pfx`, if any.
+fn load_identity(gui: &Rc<RefCell<dyn Gui>>, cert: Option<&Path>, key: Option<&Path>, pfx: Option<&Path>, pfx_password: Option<&str>) -> Result<Option<reqwest::Identity>, ()> {
+    let read = |path: &Path| match std::fs::read(path) {
+        Ok(x) => Ok(x),
+        Err(x) => {
+            gui.borrow_mut().do_error("Invalid client certificate", &format!("Couldn't read the client certificate. The path was:\n{:?}\nand the error was:\n{}", path, x));
+            Err(())
+        },
+    };
+    let identity = match (cert, key, pfx) {
+        (None, None, None) => return Ok(None),
+        (Some(cert), Some(key), None) => {
+            let (cert_pem, key_pem) = (read(cert)?, read(key)?);
+            reqwest::Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+        },
+        (None, None, Some(pfx)) => {
+            reqwest::Identity::from_pkcs12_der(&read(pfx)?, pfx_password.unwrap_or(""))
+        },
+        (_, _, Some(_)) => {
+            gui.borrow_mut().do_error("Invalid client certificate", "Use either --client-pfx, or --client-cert and --client-key, but not both.");
             return Err(());
         },
+        _ => {
+            gui.borrow_mut().do_error("Invalid client certificate", "--client-cert and --client-key must be used together.");
+            return Err(());
+        },
+    };
+    match identity {
+        Ok(x) => Ok(Some(x)),
+        Err(x) => {
+            gui.borrow_mut().do_error("Invalid client certificate", &format!("Couldn't load the client certificate. The error was:\n{}", x));
+            Err(())
+        },
     }
-    return Ok(target_url)
 }
 
-async fn determine_tasks(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, client: &mut reqwest::Client, target_url: &Url) -> Result<(Vec<Cat>, Vec<PathBuf>), ()> {
-    gui.borrow_mut().set_progress("Downloading update index...", "", None);
-    let body = match client.get(target_url.clone()).send().await {
-        Ok(x) if x.status() == 200 => x.bytes().await.unwrap(),
+/// Makes the `Authorization` header for `--token`, `TOKEN=`, or
+/// `TOKEN_FILE=`.
+fn load_token(gui: &Rc<RefCell<dyn Gui>>, token: Option<String>, token_file: Option<&Path>) -> Result<Option<reqwest::header::HeaderValue>, ()> {
+    let token = match (token, token_file) {
+        (Some(x), _) => x,
+        (None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(x) => x.trim().to_string(),
+            Err(x) => {
+                gui.borrow_mut().do_error("Invalid token", &format!("Couldn't read the token file. The path was:\n{:?}\nand the error was:\n{}", path, x));
+                return Err(());
+            },
+        },
+        (None, None) => return Ok(None),
+    };
+    match reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token)) {
+        Ok(mut x) => {
+            x.set_sensitive(true);
+            Ok(Some(x))
+        },
+        Err(_) => {
+            gui.borrow_mut().do_error("Invalid token", "The token contains characters that can't be sent in an HTTP header.");
+            Err(())
+        },
+    }
+}
+
+/// Decodes an Ed25519 public key, for `--public-key` or `PUBLIC_KEY=`.
+fn load_public_key(gui: &Rc<RefCell<dyn Gui>>, text: &str) -> Result<VerifyingKey, ()> {
+    let key = base64::engine::general_purpose::STANDARD.decode(text.trim()).ok()
+        .and_then(|x| <[u8; PUBLIC_KEY_LENGTH]>::try_from(x).ok())
+        .and_then(|x| VerifyingKey::from_bytes(&x).ok());
+    match key {
+        Some(x) => Ok(x),
+        None => {
+            gui.borrow_mut().do_error("Invalid public key", &format!("The public key for checking catalog signatures isn't valid. It should be a base64-encoded Ed25519 public key. The key was:\n{}", text));
+            Err(())
+        },
+    }
+}
+
+/// Checks the signature that makes up the last 64 bytes of a signed catalog.
+/// Returns the rest of the catalog if it checks out.
+fn verify_catalog<'a>(public_key: &VerifyingKey, body: &'a [u8]) -> Option<&'a [u8]> {
+    if body.len() < SIGNATURE_LENGTH { return None }
+    let (body, signature) = body.split_at(body.len() - SIGNATURE_LENGTH);
+    let signature = Signature::from_bytes(signature.try_into().unwrap());
+    public_key.verify_strict(body, &signature).ok()?;
+    Some(body)
+}
+
+/// Describes an error from reqwest in a way that is hopefully meaningful to
+/// an end user.
+fn describe_http_error(x: &reqwest::Error) -> String {
+    if x.is_timeout() { "connection timed out".to_string() }
+    else { format!("{}", x) }
+}
+
+/// How hard to try when a request fails in a way that might be temporary.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    /// How many times to retry before giving up.
+    retries: u32,
+    /// How many seconds to wait before the first retry. Doubles with every
+    /// subsequent retry.
+    delay: f64,
+}
+
+/// Longest we'll wait between retries, even if the server asks for longer.
+const MAX_RETRY_DELAY: f64 = 60.0;
+
+/// Parses a `Retry-After` header: either a whole number of seconds, or an
+/// HTTP date. Returns how many seconds to wait, no more than
+/// `MAX_RETRY_DELAY`.
+fn parse_retry_after(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let seconds = match value.parse::<u64>() {
+        Ok(x) => x as f64,
+        Err(_) => {
+            let when = httpdate::parse_http_date(value).ok()?;
+            // A date in the past means "now".
+            when.duration_since(SystemTime::now()).map(|x| x.as_secs_f64()).unwrap_or(0.0)
+        },
+    };
+    Some(seconds.min(MAX_RETRY_DELAY))
+}
+
+/// Sends a request. If it fails in a way that might go away by itself (server
+/// errors, timeouts, connection problems, being told to slow down), waits a
+/// while and tries again, according to `retry`. Returns whatever the last
+/// attempt got.
+async fn send_with_retries(retry: RetryPolicy, url: &Url, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let mut attempt = 0;
+    loop {
+        // None of our requests have a body, so this can't fail.
+        let result = request.try_clone().unwrap().send().await;
+        if attempt >= retry.retries { return result }
+        let backoff = (retry.delay * 2f64.powi(attempt as i32)).min(MAX_RETRY_DELAY);
+        let wait = match &result {
+            Ok(x) if x.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = x.headers().get(reqwest::header::RETRY_AFTER)
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(parse_retry_after);
+                Some((format!("{}", x.status()), retry_after.unwrap_or(backoff)))
+            },
+            Ok(x) if x.status().is_server_error() => Some((format!("{}", x.status()), backoff)),
+            Err(x) if x.is_timeout() || x.is_connect() || x.is_request() => Some((format!("{}", x), backoff)),
+            _ => None,
+        };
+        let (why, wait) = match wait {
+            Some(x) => x,
+            None => return result,
+        };
+        debug!("{}: {}; retrying in {:.1} seconds", url, why, wait);
+        tokio::time::sleep(Duration::from_secs_f64(wait.max(0.0))).await;
+        attempt += 1;
+    }
+}
+
+async fn fetch_catalog(gui: &Rc<RefCell<dyn Gui>>, client: &reqwest::Client, retry: RetryPolicy, cache: Option<&CatalogCache>, caturl: &Url) -> Result<Vec<u8>, ()> {
+    let cached = cache.and_then(|x| x.load(caturl));
+    let mut request = client.get(caturl.clone());
+    if let Some(cached) = cached.as_ref() {
+        if let Some(etag) = cached.etag.as_ref() {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = cached.last_modified.as_ref() {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = match send_with_retries(retry, caturl, request).await {
+        Ok(x) if x.status() == 304 && cached.is_some() => {
+            debug!("{}: not modified, using cached copy", caturl);
+            return Ok(cached.unwrap().body)
+        },
+        Ok(x) if x.status() == 200 => x,
         Ok(x) => {
-            gui.borrow_mut().do_error("Download failed", &format!("Error \"{}\" while trying to download the update index.", x.status()));
+            gui.borrow_mut().do_error_coded("Download failed", &format!("Error \"{}\" while trying to download an update catalog.", x.status()), ERR_CATALOG_STATUS);
             return Err(());
         },
         Err(x) => {
-            gui.borrow_mut().do_error("Download failed", &format!("Couldn't download the update index. The error was:\n{}", x));
+            gui.borrow_mut().do_error_coded("Download failed", &format!("Couldn't download an update catalog. The error was:\n{}", describe_http_error(&x)), ERR_CATALOG_TRANSFER);
             return Err(());
         },
     };
+    let header = |name| response.headers().get(name).and_then(|x| x.to_str().ok()).map(str::to_string);
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+    match response.bytes().await {
+        Ok(x) => {
+            if let Some(cache) = cache {
+                if etag.is_some() || last_modified.is_some() {
+                    cache.store(caturl, etag.as_deref(), last_modified.as_deref(), &x);
+                }
+            }
+            Ok(x.to_vec())
+        },
+        Err(x) => {
+            gui.borrow_mut().do_error_coded("Download failed", &format!("Couldn't download an update catalog. The error was:\n{}", describe_http_error(&x)), ERR_CATALOG_TRANSFER);
+            Err(())
+        },
+    }
+}
+
+/// Downloads the update index from the first of `target_urls` that will give
+/// it to us. Returns the index, and the URL it came from.
+async fn fetch_index<'a>(gui: &Rc<RefCell<dyn Gui>>, client: &reqwest::Client, retry: RetryPolicy, target_urls: &'a [Url]) -> Result<(Vec<u8>, &'a Url), ()> {
+    let mut errors = vec![];
+    for target_url in target_urls.iter() {
+        let error = match send_with_retries(retry, target_url, client.get(target_url.clone())).await {
+            Ok(x) if x.status() == 200 => match x.bytes().await {
+                Ok(x) => return Ok((x.to_vec(), target_url)),
+                Err(x) => describe_http_error(&x),
+            },
+            Ok(x) => format!("Error \"{}\"", x.status()),
+            Err(x) => describe_http_error(&x),
+        };
+        debug!("failed to download {}: {}", target_url, error);
+        errors.push((target_url, error));
+    }
+    if errors.len() == 1 {
+        gui.borrow_mut().do_error_coded("Download failed", &format!("Couldn't download the update index. The error was:\n{}", errors[0].1), ERR_INDEX_TRANSFER);
+    }
+    else {
+        let errors: Vec<String> = errors.into_iter().map(|(url, error)| format!("{}: {}", url, error)).collect();
+        gui.borrow_mut().do_error_coded("Download failed", &format!("Couldn't download the update index from any of the mirrors. The errors were:\n{}", errors.join("\n")), ERR_INDEX_TRANSFER);
+    }
+    Err(())
+}
+
+/// Everything `determine_tasks` needs to know about how to fetch and trust
+/// the index and catalogs.
+#[derive(Clone, Copy)]
+struct CatalogSettings<'a> {
+    retry: RetryPolicy,
+    /// How many catalogs to download at once.
+    catalog_jobs: usize,
+    /// How many Lua instructions the index may run.
+    lua_limit: u64,
+    /// Given with `--basedir`.
+    basedir_override: Option<&'a Path>,
+    channel: &'a str,
+    /// Hosts other than the index's own that catalogs may come from.
+    allowed_hosts: &'a [String],
+    cache: Option<&'a CatalogCache>,
+    /// If present, every catalog must be signed with this key.
+    public_key: Option<&'a VerifyingKey>,
+}
+
+async fn determine_tasks(gui: &Rc<RefCell<dyn Gui>>, client: &mut reqwest::Client, settings: &CatalogSettings<'_>, target_urls: &[Url]) -> Result<(Vec<Cat>, Vec<PathBuf>, Vec<PathBuf>), TupdateError> {
+    let CatalogSettings { retry, catalog_jobs, lua_limit, basedir_override, channel, allowed_hosts, cache, public_key } = *settings;
+    gui.borrow_mut().set_progress("Downloading update index...", "", None);
+    let (body, target_url) = fetch_index(gui, client, retry, target_urls).await.map_err(|_| TupdateError::NetworkError)?;
     gui.borrow_mut().set_progress("Determining files to update...", "", None);
-    let (installs, deletes) = match find_updates(gui.clone(), verbose, &body[..], target_url.clone()) {
+    let (installs, deletes) = match find_updates(gui.clone(), &body[..], target_url.clone(), lua_limit, basedir_override, channel) {
         Ok(x) => x,
-        Err(_) => return Err(()),
+        Err(x) => {
+            match x {
+                FindUpdatesError::LuaInitFailed(x) => gui.borrow_mut().do_error("Internal error", &format!("Unable to initialize Lua. The error was:\n{}", x)),
+                FindUpdatesError::ScriptError(x) => gui.borrow_mut().do_error("Lua error", &format!("An error occurred while processing the update index. The error was:\n{}", x)),
+                FindUpdatesError::BailOut => (),
+                FindUpdatesError::InstructionLimit => gui.borrow_mut().do_error("Update index error", "The update index script ran for too long and was aborted."),
+                FindUpdatesError::SecurityViolation(x) => gui.borrow_mut().do_error("Security error", &x),
+            }
+            return Err(TupdateError::LuaError)
+        },
     };
+    // Catalogs may only come from the server the index came from, or
+    // somewhere the user has explicitly trusted.
+    for (_, caturl) in installs.iter() {
+        let host = caturl.host_str();
+        let trusted = target_url.host_str() == host
+            || host.map(|host| allowed_hosts.iter().any(|x| x.eq_ignore_ascii_case(host))).unwrap_or(false);
+        if !trusted {
+            debug!("{}: untrusted host", caturl);
+            gui.borrow_mut().do_error("Security error", &format!("The update index asked for a catalog from a server it didn't come from. If this is intended, use --allow-host to trust that server.\nThe catalog is: {}", caturl));
+            return Err(TupdateError::LuaError);
+        }
+    }
+    let mut basedirs: Vec<PathBuf> = vec![];
+    for basedir in installs.iter().map(|(x, _)| x).chain(deletes.keys()) {
+        if !basedirs.contains(basedir) {
+            basedirs.push(basedir.clone());
+        }
+    }
     let mut all_deletions = vec![];
     for (base, globs) in deletes.into_iter() {
         for glob in globs.into_iter() {
@@ -184,7 +874,7 @@ async fn determine_tasks(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, client: &mut
                             }
                         }
                         gui.borrow_mut().do_error("Error checking files to delete", &format!("An error occurred while trying to look through files we might need to delete. The error was:\n{}", x));
-                        return Err(())
+                        return Err(TupdateError::IoError)
                     },
                 };
                 all_deletions.push(path);
@@ -195,139 +885,268 @@ async fn determine_tasks(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, client: &mut
         a.path().cmp(&b.path())
     });
     all_deletions.dedup_by(|a,b| { a.path() == b.path() });
-    let mut all_cats = vec![];
+    // Fetch all the catalogs first, several at a time. Decompressing and
+    // parsing them is CPU-bound, so that happens one at a time afterward.
     let mut patience = Patience::new();
-    for (n, (basedir, caturl)) in installs.iter().enumerate() {
+    if patience.force() {
+        gui.borrow_mut().set_progress("Downloading update catalogs...", &format!("0/{}", installs.len()), Some(0.0));
+    }
+    let client = &*client;
+    let mut fetches = futures_util::stream::iter(installs.iter())
+        .map(|(_, caturl)| fetch_catalog(gui, client, retry, cache, caturl))
+        .buffered(catalog_jobs.max(1));
+    let mut bodies = Vec::with_capacity(installs.len());
+    while let Some(body) = fetches.next().await {
+        bodies.push(body.map_err(|_| TupdateError::NetworkError)?);
         if patience.have_been_patient() {
-            gui.borrow_mut().set_progress("Downloading update catalogs...", &format!("{}/{} {}", n+1, installs.len(), caturl), Some(n as f32 / installs.len() as f32));
+            gui.borrow_mut().set_progress("Downloading update catalogs...", &format!("{}/{}", bodies.len(), installs.len()), Some(bodies.len() as f32 / installs.len() as f32));
         }
-        let body = match client.get(caturl.clone()).send().await {
-            Ok(x) if x.status() == 200 => x.bytes().await.unwrap(),
-            Ok(x) => {
-                gui.borrow_mut().do_error("Download failed", &format!("Error \"{}\" while trying to download an update catalog.", x.status()));
-                return Err(());
-            },
-            Err(x) => {
-                gui.borrow_mut().do_error("Download failed", &format!("Couldn't download an update catalog. The error was:\n{}", x));
-                return Err(());
-            },
-        };
+    }
+    drop(fetches);
+    let mut headers = Vec::with_capacity(bodies.len());
+    for ((_, caturl), body) in installs.iter().zip(bodies.iter()) {
         if body.len() == 0 {
-            if verbose {
-                gui.borrow_mut().verbose(&format!("{}: empty cat body", caturl));
-            }
+            debug!("{}: empty cat body", caturl);
             gui.borrow_mut().do_error("Missing catalog", &format!("A catalog file was completely empty. This may indicate that the update server is being updated. Try again in a few minutes.\nThe corrupted catalog is: {}", caturl));
-            return Err(());
+            return Err(TupdateError::CatalogError);
         }
-        if &body[..5] != b"\xFFTCat" {
-            if verbose {
-                gui.borrow_mut().verbose(&format!("{}: invalid cat header", caturl));
-            }
-            gui.borrow_mut().do_error("Invalid catalog", &format!("A catalog file was invalid. This is a problem with the update server. Try again in a few minutes.\nThe corrupted catalog is: {}", caturl));
-            return Err(());
+        let body = match public_key {
+            None => &body[..],
+            Some(public_key) => match verify_catalog(public_key, body) {
+                Some(x) => x,
+                None => {
+                    debug!("{}: bad signature", caturl);
+                    gui.borrow_mut().do_error("Invalid catalog", &format!("Catalog signature invalid—possible tampering. The update server, or something between it and you, may have been compromised.\nThe catalog is: {}", caturl));
+                    return Err(TupdateError::CatalogError);
+                },
+            },
+        };
+        match CatHeader::try_parse(body) {
+            Some(x) => headers.push(x),
+            None => {
+                debug!("{}: invalid cat header", caturl);
+                gui.borrow_mut().do_error("Invalid catalog", &format!("A catalog file was invalid. This is a problem with the update server. Try again in a few minutes.\nThe corrupted catalog is: {}", caturl));
+                return Err(TupdateError::CatalogError);
+            },
         }
-        let checksum = &body[5..37];
-        let uncompressed_size = u32::from_be_bytes(body[37..41].try_into().unwrap()) as usize;
-        let mut uncompressed = Vec::with_capacity(uncompressed_size as usize);
-        let mut reader = flate2::read::ZlibDecoder::new(&body[41..]);
-        if reader.read_to_end(&mut uncompressed).is_err() || uncompressed.len() != uncompressed_size || lsx::sha256::hash(&uncompressed) != checksum {
-            if verbose {
-                gui.borrow_mut().verbose(&format!("{}: failed decompression", caturl));
-            }
+    }
+    // These come straight from the headers, so they're only good for
+    // logging until the bodies have been checked.
+    let announced_count = headers.iter().filter_map(|x| x.entry_count).fold(0usize, |a,x| a.saturating_add(x as usize));
+    let announced_bytes = headers.iter().filter_map(|x| x.payload_bytes).fold(0u64, |a,x| a.saturating_add(x));
+    if announced_count > 0 {
+        debug!("catalogs announce {} entries, {} total", announced_count, format_bytes(announced_bytes));
+    }
+    let mut all_cats = Vec::new();
+    for ((basedir, caturl), header) in installs.iter().zip(headers) {
+        let uncompressed_size = header.uncompressed_size;
+        let uncompressed = match header.compression {
+            Compression::Zlib => {
+                // Deflate can't do better than about 1032:1, so anything
+                // claiming more than that is lying.
+                let mut uncompressed = Vec::with_capacity(uncompressed_size.min(header.compressed.len().saturating_mul(1032)));
+                // One byte more than expected is enough to know it's wrong.
+                let mut reader = flate2::read::ZlibDecoder::new(header.compressed).take(uncompressed_size as u64 + 1);
+                reader.read_to_end(&mut uncompressed).map(|_| uncompressed)
+            },
+            #[cfg(feature="zstd-cats")]
+            Compression::Zstd => zstd::stream::decode_all(header.compressed),
+            #[cfg(not(feature="zstd-cats"))]
+            Compression::Zstd => Err(std::io::Error::new(ErrorKind::Unsupported, "this build of the updater can't read zstd-compressed catalogs")),
+        };
+        let uncompressed = match uncompressed {
+            Ok(x) => x,
+            Err(x) => {
+                debug!("{}: failed decompression: {}", caturl, x);
+                vec![]
+            },
+        };
+        if uncompressed.len() != uncompressed_size || lsx::sha256::hash(&uncompressed) != header.checksum {
+            debug!("{}: failed decompression", caturl);
             gui.borrow_mut().do_error("Invalid catalog", &format!("A catalog file was invalid. This is a problem with the update server. Try again in a few minutes.\nThe corrupted catalog is: {}", caturl));
-            return Err(());
+            return Err(TupdateError::CatalogError);
         }
+        // Now that the body checks out, the count can't be more than it
+        // could actually hold.
+        all_cats.reserve(header.entry_count.map(|x| x as usize).unwrap_or(0).min(uncompressed.len() / MIN_CAT_ENTRY_SIZE));
+        let first_cat = all_cats.len();
         let mut next: &[u8] = &uncompressed;
         while next.len() > 0 {
             let (cat, rem) = match Cat::try_parse(next, &caturl, basedir) {
                 Ok(x) => x,
                 Err(_) => {
-                    if verbose {
-                        gui.borrow_mut().verbose(&format!("{}: failed cat parsing", caturl));
-                    }
+                    debug!("{}: failed cat parsing", caturl);
                     gui.borrow_mut().do_error("Invalid catalog", &format!("A catalog file was invalid. This is a problem with the update server. Try again in a few minutes.\nThe corrupted catalog is: {}", caturl));
-                    return Err(());
+                    return Err(TupdateError::CatalogError);
                 },
             };
             all_cats.push(cat);
             next = rem;
         }
+        if header.entry_count.map(|x| x as usize != all_cats.len() - first_cat).unwrap_or(false) {
+            debug!("{}: entry count doesn't match header", caturl);
+            gui.borrow_mut().do_error("Invalid catalog", &format!("A catalog file was invalid. This is a problem with the update server. Try again in a few minutes.\nThe corrupted catalog is: {}", caturl));
+            return Err(TupdateError::CatalogError);
+        }
+    }
+    // Overlapping catalogs may list the same file. There's no sense checking
+    // or downloading it twice. If they disagree about what it should be,
+    // though, we can't satisfy both.
+    let mut seen: HashMap<&Path, &Cat> = HashMap::with_capacity(all_cats.len());
+    for cat in all_cats.iter() {
+        match seen.entry(&cat.dst_path) {
+            HashMapEntry::Vacant(x) => { x.insert(cat); },
+            HashMapEntry::Occupied(x) if x.get().checksum == cat.checksum && x.get().kind == cat.kind => (),
+            HashMapEntry::Occupied(_) => {
+                debug!("{:?}: listed more than once, differently", cat.dst_path);
+                gui.borrow_mut().do_error("Invalid catalog", &format!("Two catalogs disagree about what one of the files should contain. This is a problem with the update server.\nThe file is: {:?}", cat.dst_path));
+                return Err(TupdateError::CatalogError);
+            },
+        }
+    }
+    drop(seen);
+    let mut seen = HashSet::with_capacity(all_cats.len());
+    all_cats.retain(|cat| {
+        let new = seen.insert(cat.dst_path.clone());
+        if !new { debug!("{:?}: listed more than once", cat.dst_path) }
+        new
+    });
+    // `Cat::try_parse` already keeps each entry inside its own basedir. This
+    // is a last line of defense, in case that ever stops being true.
+    if let Some(cat) = all_cats.iter().find(|cat| !basedirs.iter().any(|x| cat.dst_path.starts_with(x))) {
+        debug!("{:?}: outside of every basedir", cat.dst_path);
+        gui.borrow_mut().do_error("Security error", "A catalog entry references a path outside of any known installation directory.");
+        return Err(TupdateError::CatalogError);
+    }
+    if let Some(cat) = find_chained_symlink(&all_cats) {
+        debug!("{:?}: goes through another symlink in the catalogs", cat.dst_path);
+        gui.borrow_mut().do_error("Security error", "A catalog entry goes through a symlink listed in the catalogs, which could lead outside of the installation directory.");
+        return Err(TupdateError::CatalogError);
     }
-    Ok((all_cats, all_deletions.into_iter().map(|x| x.into_path()).collect()))
+    Ok((all_cats, all_deletions.into_iter().map(|x| x.into_path()).collect(), basedirs))
 }
 
-fn find_cat_statuses(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, all_cats: &mut Vec<Cat>) -> Result<(),()> {
-    gui.borrow_mut().set_progress("Examining local files...", "", Some(0.0));
-    let gui = &mut *gui.borrow_mut();
-    let gui = Mutex::new(gui);
-    let n = AtomicUsize::new(0);
-    let num_cats = all_cats.len();
-    all_cats.par_iter_mut().for_each(|cat| {
-        let progn = n.fetch_add(1, AtomicOrdering::SeqCst);
-        let testn = n.load(AtomicOrdering::SeqCst);
-        if testn == progn {
-            gui.lock().unwrap().set_progress("Examining local files...", "", Some(testn as f32 / num_cats as f32));
-        }
-        let meta = match std::fs::metadata(&cat.dst_path) {
-            Ok(x) => x,
+/// How often to check on the workers in `find_cat_statuses`.
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Checks one catalog entry against the local file, marking it for download
+/// if it doesn't match. Adds the bytes it reads to `examined` as it goes, and
+/// returns how many that was.
+fn examine_cat(cat: &mut Cat, examined: &AtomicU64) -> u64 {
+    if let CatKind::Symlink(target) = &cat.kind {
+        match read_symlink(&cat.dst_path) {
+            Ok(x) if &x == target => (),
+            Ok(_) => {
+                debug!("{:?}: symlink target does not match", &cat.dst_path);
+                cat.needs_download = true;
+                cat.mismatch = Some(Mismatch::Target);
+            },
             Err(x) => {
-                if x.kind() != ErrorKind::NotFound && verbose {
-                    gui.lock().unwrap()
-                    .verbose(&format!("{:?}: error getting metadata: {}", &cat.dst_path, x));
+                if x.kind() != ErrorKind::NotFound {
+                    debug!("{:?}: error reading symlink: {}", &cat.dst_path, x);
                 }
                 cat.needs_download = true;
-                return;
+                cat.mismatch = Some(if x.kind() == ErrorKind::NotFound { Mismatch::Missing } else { Mismatch::Target });
             },
-        };
-        if meta.len() != cat.size {
-            if verbose {
-                gui.lock().unwrap()
-                .verbose(&format!("{:?}: size does not match", &cat.dst_path));
+        }
+        return 0;
+    }
+    let meta = match std::fs::metadata(&cat.dst_path) {
+        Ok(x) => x,
+        Err(x) => {
+            if x.kind() != ErrorKind::NotFound {
+                debug!("{:?}: error getting metadata: {}", &cat.dst_path, x);
             }
             cat.needs_download = true;
-            return;
-        }
-        let mut f = match File::open(&cat.dst_path) {
+            cat.mismatch = Some(if x.kind() == ErrorKind::NotFound { Mismatch::Missing } else { Mismatch::Unreadable });
+            return 0;
+        },
+    };
+    if meta.len() != cat.size {
+        debug!("{:?}: size does not match", &cat.dst_path);
+        cat.needs_download = true;
+        cat.mismatch = Some(Mismatch::Size);
+        return 0;
+    }
+    let mut f = match File::open(&cat.dst_path) {
+        Ok(x) => x,
+        Err(x) => {
+            if x.kind() != ErrorKind::NotFound {
+                debug!("{:?}: error opening file: {}", &cat.dst_path, x);
+            }
+            cat.needs_download = true;
+            cat.mismatch = Some(if x.kind() == ErrorKind::NotFound { Mismatch::Missing } else { Mismatch::Unreadable });
+            return 0;
+        },
+    };
+    let mut hasher = lsx::sha256::BufSha256::new();
+    let mut counted = 0;
+    let mut buf = [0u8; 32768];
+    loop {
+        let red = match f.read(&mut buf[..]) {
+            Ok(0) => break,
             Ok(x) => x,
             Err(x) => {
-                if x.kind() != ErrorKind::NotFound && verbose {
-                    gui.lock().unwrap()
-                    .verbose(&format!("{:?}: error opening file: {}", &cat.dst_path, x));
-                }
+                debug!("{:?}: error while reading: {}", &cat.dst_path, x);
                 cat.needs_download = true;
-                return;
+                cat.mismatch = Some(Mismatch::Unreadable);
+                return counted;
             },
         };
-        let mut hasher = lsx::sha256::BufSha256::new();
-        let mut buf = [0u8; 32768];
-        loop {
-            let red = match f.read(&mut buf[..]) {
-                Ok(0) => break,
-                Ok(x) => x,
-                Err(x) => {
-                    if verbose {
-                        gui.lock().unwrap()
-                        .verbose(&format!("{:?}: error while reading: {}", &cat.dst_path, x));
-                    }
-                    cat.needs_download = true;
-                    return;
-                },
-            };
-            hasher.update(&buf[..red]);
+        hasher.update(&buf[..red]);
+        examined.fetch_add(red as u64, AtomicOrdering::Relaxed);
+        counted += red as u64;
+    }
+    let checksum = hasher.finish(&[]);
+    if checksum != cat.checksum {
+        debug!("{:?}: checksum does not match", &cat.dst_path);
+        cat.needs_download = true;
+        cat.mismatch = Some(Mismatch::Checksum);
+    }
+    counted
+}
+
+fn find_cat_statuses(gui: &Rc<RefCell<dyn Gui>>, repair: bool, all_cats: &mut Vec<Cat>) -> Result<(),TupdateError> {
+    if repair {
+        for cat in all_cats.iter_mut() {
+            debug!("{:?}: repair mode: skipping local verification", &cat.dst_path);
+            cat.needs_download = true;
         }
-        let checksum = hasher.finish(&[]);
-        if checksum != cat.checksum {
-            if verbose {
-                gui.lock().unwrap()
-                .verbose(&format!("{:?}: checksum does not match", &cat.dst_path));
+        return Ok(())
+    }
+    let mut patience = Patience::new();
+    if patience.force() {
+        gui.borrow_mut().set_progress("Examining local files...", "", Some(0.0));
+    }
+    let total_bytes = all_cats.iter().fold(0u64, |a,x| a.saturating_add(x.size)).max(1);
+    let examined = AtomicU64::new(0);
+    // The workers don't touch the GUI at all. This thread watches how far
+    // they've gotten, and reports it.
+    std::thread::scope(|scope| {
+        let examined = &examined;
+        let workers = scope.spawn(move || {
+            all_cats.par_iter_mut().for_each(|cat| {
+                let counted = examine_cat(cat, examined);
+                // Whatever we skipped reading counts as examined, too.
+                examined.fetch_add(cat.size.saturating_sub(counted), AtomicOrdering::Relaxed);
+            });
+        });
+        while !workers.is_finished() {
+            std::thread::sleep(STATUS_POLL_INTERVAL);
+            if patience.have_been_patient() {
+                let progress = examined.load(AtomicOrdering::Relaxed) as f32 / total_bytes as f32;
+                gui.borrow_mut().set_progress("Examining local files...", "", Some(progress));
             }
-            cat.needs_download = true;
         }
     });
+    // Anything logged by the worker threads has been waiting for the GUI.
+    logging::flush();
     Ok(())
 }
 
-fn trim_deletions(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, all_cats: &mut Vec<Cat>, all_deletions: &mut Vec<PathBuf>) {
+fn trim_deletions(all_cats: &mut Vec<Cat>, all_deletions: &mut Vec<PathBuf>) {
+    // Don't pull the lock out from under ourselves.
+    all_deletions.retain(|x| x.file_name() != Some(OsStr::new(LOCK_FILE_NAME)));
     for cat in all_cats.iter() {
         let mut pat = Some(cat.dst_path.as_path());
         while let Some(dis) = pat {
@@ -338,171 +1157,1186 @@ fn trim_deletions(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, all_cats: &mut Vec<
             }
             pat = dis.parent();
         }
-    }
-    if verbose {
-        let mut gui = gui.borrow_mut();
-        for deletion in all_deletions.iter() {
-            gui.verbose(&format!("will delete: {:?}", deletion));
+        #[cfg(not(unix))]
+        if let CatKind::Symlink(_) = cat.kind {
+            let stand_in = symlink_stand_in_path(&cat.dst_path);
+            all_deletions.retain(|x| *x != stand_in);
         }
-        for cat in all_cats.iter() {
-            if cat.needs_download {
-                gui.verbose(&format!("will download: {:?} <- {}", cat.dst_path, cat.src_url));
-            }
+    }
+    for deletion in all_deletions.iter() {
+        info!("will delete: {:?}", deletion);
+    }
+    for cat in all_cats.iter() {
+        if cat.needs_download {
+            info!("will download: {:?} <- {}", cat.dst_path, cat.src_url);
         }
     }
 }
 
-const SECONDS_PER_DAY: f64 = 86400.0;
+const LOCK_FILE_NAME: &str = ".tupdate.lock";
 
-fn calc_rate_and_eta(start_time: Instant, now: Instant, got_so_far: u64, total_to_get: u64) -> String {
-    if start_time > now { return "?????????".to_string() }
-    let time_so_far = (now - start_time).as_secs_f64();
-    if time_so_far < 1.0 || got_so_far >= total_to_get { return "...".to_string() }
-    let bytes_per_second = got_so_far as f64 / time_so_far;
-    let remaining_seconds = (total_to_get - got_so_far) as f64 / bytes_per_second;
-    let eta = if remaining_seconds >= 100000.0 {
-        let num_days = (remaining_seconds / SECONDS_PER_DAY).floor() as u64;
-        if num_days == 1 { format!("over a day left") }
-        else { format!("over {} days left", num_days) }
-    } else {
-        let seconds = remaining_seconds.floor() as u32;
-        format!("{}:{:02}:{:02} left", seconds / 60 / 60, (seconds / 60) % 60, seconds % 60)
-    };
-    let rate = if bytes_per_second > 1000000000.0 { format!("Wow!") }
-    else if bytes_per_second > 800000.0 { format!("{:.1}MB/s", bytes_per_second / 1000000.0) }
-    else if bytes_per_second > 800.0 { format!("{:.1}kB/s", bytes_per_second / 1000.0) }
-    else { format!("{:.1}B/s", bytes_per_second) };
-    format!("{}, {}", rate, eta)
+/// An exclusive lock on a directory we're updating, to keep two updaters from
+/// stepping on each other's toes. Released when dropped.
+struct UpdateLock {
+    file: File,
 }
 
-async fn perform_downloads(gui: &Rc<RefCell<dyn Gui>>, verbose: bool, client: &mut reqwest::Client, all_cats: Vec<Cat>) -> Result<(),()> {
-    let total_cat_bytes = all_cats.iter().fold(0, |a,x| a + if x.needs_download { x.size } else { 0 });
-    let mut total_recvd_bytes = 0;
-    let start_time = Instant::now();
-    let mut patience = Patience::new();
-    for cat in all_cats.into_iter() {
-        if !cat.needs_download { continue }
-        let mut response = match client.get(cat.src_url.clone()).send().await {
-            Ok(x) if x.status() == 200 => x,
-            Ok(x) => {
-                if verbose {
-                    gui.borrow_mut().verbose(&format!("failed to download {}", &cat.src_url));
-                }
-                gui.borrow_mut().do_error("Download failed", &format!("Error \"{}\" while trying to download an updated file.", x.status()));
-                return Err(());
-            },
+impl UpdateLock {
+    fn take(gui: &Rc<RefCell<dyn Gui>>, basedir: &Path) -> Result<UpdateLock, ()> {
+        let path = basedir.join(LOCK_FILE_NAME);
+        debug!("Locking {:?}", path);
+        let _ = std::fs::create_dir_all(basedir);
+        let file = match OpenOptions::new().write(true).create(true).truncate(false).open(&path) {
+            Ok(x) => x,
             Err(x) => {
-                if verbose {
-                    gui.borrow_mut().verbose(&format!("failed to download {}", &cat.src_url));
-                }
-                gui.borrow_mut().do_error("Download failed", &format!("Couldn't download an updated file. The error was:\n{}", x));
+                gui.borrow_mut().do_error("Update failed", &format!("Couldn't create the lock file. The path was:\n{:?}\nand the error was:\n{}", path, x));
                 return Err(());
             },
         };
-        let _ = std::fs::create_dir_all(cat.dst_path.parent().unwrap());
-        let mut f = match File::create(&cat.dst_path) {
-            Ok(x) => x,
+        match file.try_lock_exclusive() {
+            Ok(_) => Ok(UpdateLock { file }),
+            Err(x) if x.kind() == fs2::lock_contended_error().kind() => {
+                gui.borrow_mut().do_error("Update failed", "Another update is already in progress. Wait for it to finish, then try again.");
+                Err(())
+            },
             Err(x) => {
-                gui.borrow_mut().do_error("Update failed", &format!("Couldn't open one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", cat.dst_path, x));
-                return Err(());
+                gui.borrow_mut().do_error("Update failed", &format!("Couldn't lock the lock file. The path was:\n{:?}\nand the error was:\n{}", path, x));
+                Err(())
             },
-        };
-        let mut file_recvd_bytes = 0;
-        let mut file_hasher = lsx::sha256::BufSha256::new();
-        while file_recvd_bytes <= cat.size {
-            let now = Instant::now();
-            let rate_and_eta = calc_rate_and_eta(start_time, now, total_recvd_bytes, total_cat_bytes);
-            if patience.have_been_patient() {
-                gui.borrow_mut().set_progress("Downloading updates...", &rate_and_eta, Some(total_recvd_bytes as f32 / total_cat_bytes as f32));
+        }
+    }
+}
+
+impl Drop for UpdateLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// Returns the number of bytes we may write to the filesystem that contains
+/// `path`, if that can be determined.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    Some(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[cfg(windows)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    let ok = unsafe {
+        windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW(path.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut())
+    };
+    if ok != 0 { Some(available) } else { None }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Makes sure there's room for everything we're about to download, and gives
+/// the user a chance to back out if there isn't. Returns true if we should
+/// proceed.
+fn check_disk_space(gui: &Rc<RefCell<dyn Gui>>, all_cats: &[Cat]) -> bool {
+    let total_download: u64 = all_cats.iter().filter(|c| c.needs_download).fold(0, |a,c| a.saturating_add(c.size));
+    let first_path = match all_cats.iter().find(|c| c.needs_download) {
+        Some(x) => &x.dst_path,
+        None => return true,
+    };
+    // The file (and maybe some of its parents) might not exist yet. Ask about
+    // the nearest directory that does.
+    let available = first_path.ancestors().skip(1).find(|x| x.exists()).and_then(available_space);
+    let available = match available {
+        Some(x) => x,
+        None => {
+            debug!("Couldn't determine the free space available for {:?}, not checking it", first_path);
+            return true
+        },
+    };
+    debug!("Need {} bytes, have {} bytes available", total_download, available);
+    // Leave a 10% safety margin.
+    if available - available / 10 < total_download {
+        gui.borrow_mut().do_warning("Low disk space", &format!("This update needs to download {} bytes, but there are only {} bytes free. The update may fail partway through if you continue.", total_download, available), true)
+    }
+    else { true }
+}
+
+/// Returns the path of the temporary file that a download of `path` is
+/// written into before being renamed into place. It lives in the same
+/// directory, so the rename never has to cross filesystems.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Formats a number of bytes for human consumption, e.g. "12.1 MB".
+fn format_bytes(n: u64) -> String {
+    if n >= 800000000 { format!("{:.1} GB", n as f64 / 1000000000.0) }
+    else if n >= 800000 { format!("{:.1} MB", n as f64 / 1000000.0) }
+    else if n >= 800 { format!("{:.1} kB", n as f64 / 1000.0) }
+    else { format!("{} B", n) }
+}
+
+/// How many `(time, total bytes received)` samples to keep for estimating the
+/// download rate. Progress updates (and therefore samples) come at most five
+/// times a second, so this covers roughly the last five seconds.
+const RATE_SAMPLES: usize = 25;
+
+/// Returns e.g. "3.4 MB / 12.1 MB — 1.2 MB/s, 0:01:23 left". The rate is
+/// averaged over the given window of recent samples, oldest first, so it
+/// follows the current speed rather than the speed since the beginning.
+fn calc_rate_and_eta(samples: &VecDeque<(Instant, u64)>, got_so_far: u64, total_to_get: u64) -> String {
+    let bytes = format!("{} / {}", format_bytes(got_so_far), format_bytes(total_to_get));
+    let (oldest, newest) = match (samples.front(), samples.back()) {
+        (Some(oldest), Some(newest)) => (oldest, newest),
+        _ => return format!("{} — ...", bytes),
+    };
+    if oldest.0 > newest.0 || oldest.1 > newest.1 { return format!("{} — ?????????", bytes) }
+    let time_in_window = (newest.0 - oldest.0).as_secs_f64();
+    if time_in_window < 1.0 || got_so_far >= total_to_get { return format!("{} — ...", bytes) }
+    let bytes_per_second = (newest.1 - oldest.1) as f64 / time_in_window;
+    if bytes_per_second < 1.0 { return format!("{} — stalled", bytes) }
+    let remaining_seconds = (total_to_get - got_so_far) as f64 / bytes_per_second;
+    let eta = if remaining_seconds >= 100000.0 {
+        let num_days = (remaining_seconds / SECONDS_PER_DAY).floor() as u64;
+        if num_days == 1 { format!("over a day left") }
+        else { format!("over {} days left", num_days) }
+    } else {
+        let seconds = remaining_seconds.floor() as u32;
+        format!("{}:{:02}:{:02} left", seconds / 60 / 60, (seconds / 60) % 60, seconds % 60)
+    };
+    let rate = if bytes_per_second > 1000000000.0 { format!("Wow!") }
+    else { format!("{}/s", format_bytes(bytes_per_second as u64)) };
+    format!("{} — {}, {}", bytes, rate, eta)
+}
+
+/// Progress that is shared between all of the downloads that are in flight at
+/// once.
+struct DownloadProgress {
+    start_time: Instant,
+    total_cat_bytes: u64,
+    total_recvd_bytes: AtomicU64,
+    patience: RefCell<Patience>,
+    /// Recent `(time, total_recvd_bytes)` samples, oldest first. At most
+    /// `RATE_SAMPLES` long.
+    rate_samples: RefCell<VecDeque<(Instant, u64)>>,
+    /// If specified, the maximum rate, in bytes per second, at which to
+    /// download (across all files).
+    max_rate: Option<u64>,
+    /// Bytes actually transferred so far, for enforcing `max_rate`. (Unlike
+    /// `total_recvd_bytes`, this doesn't count resumed partial downloads.)
+    throttled_bytes: AtomicU64,
+    /// If specified, the most bytes we may transfer in total.
+    max_total_download: Option<u64>,
+    /// Bytes actually transferred so far, for enforcing `max_total_download`.
+    transferred_bytes: AtomicU64,
+    /// Paces the "still downloading" messages in the log.
+    log_patience: RefCell<Patience>,
+    /// The name of the file we most recently started downloading.
+    current_file: RefCell<String>,
+}
+
+/// How often to log how a long download is going.
+const STILL_DOWNLOADING_INTERVAL: Duration = Duration::from_secs(30);
+
+impl DownloadProgress {
+    fn add_bytes(&self, gui: &Rc<RefCell<dyn Gui>>, amount: u64) {
+        let total_recvd_bytes = self.total_recvd_bytes.fetch_add(amount, AtomicOrdering::Relaxed) + amount;
+        if self.patience.borrow_mut().have_been_patient() {
+            let mut rate_samples = self.rate_samples.borrow_mut();
+            if rate_samples.len() >= RATE_SAMPLES {
+                rate_samples.pop_front();
             }
-            match response.chunk().await {
-                Err(x) => {
-                    gui.borrow_mut().do_error("Download failed", &format!("Error while downloading an updated file. The error was:\n{}", x));
-                    return Err(());
-                },
-                Ok(None) => break,
-                Ok(Some(x)) => {
-                    match f.write_all(&x[..]) {
-                        Ok(_) => (),
-                        Err(x) => {
-                            gui.borrow_mut().do_error("Update failed", &format!("Couldn't write to one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", cat.dst_path, x));
-                            return Err(());
-                        },
-                    }
-                    file_hasher.update(&x[..]);
-                    total_recvd_bytes += x.len() as u64;
-                    file_recvd_bytes += x.len() as u64;
-                },
+            rate_samples.push_back((Instant::now(), total_recvd_bytes));
+            let rate_and_eta = calc_rate_and_eta(&rate_samples, total_recvd_bytes, self.total_cat_bytes);
+            let current_file = self.current_file.borrow();
+            let subtask = if current_file.is_empty() { rate_and_eta }
+            else { format!("{} — {}", current_file, rate_and_eta) };
+            gui.borrow_mut().set_progress("Downloading updates...", &subtask, Some(total_recvd_bytes as f32 / self.total_cat_bytes as f32));
+        }
+        let mut log_patience = self.log_patience.borrow_mut();
+        // The first call only starts the clock.
+        if log_patience.elapsed_since_last().is_none() {
+            log_patience.force();
+        }
+        else if log_patience.have_been_patient() {
+            info!("Still downloading: {} of {} so far", format_bytes(total_recvd_bytes), format_bytes(self.total_cat_bytes));
+        }
+    }
+    /// Call when a file starts downloading, so it can be shown in the progress
+    /// display.
+    fn start_file(&self, path: &Path) {
+        *self.current_file.borrow_mut() = path.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    }
+    /// Call after receiving `amount` bytes. Returns true if that put us over
+    /// `max_total_download`.
+    fn exceeds_max_total(&self, amount: u64) -> bool {
+        let transferred_bytes = self.transferred_bytes.fetch_add(amount, AtomicOrdering::Relaxed) + amount;
+        self.max_total_download.map(|x| transferred_bytes > x).unwrap_or(false)
+    }
+    /// Call after receiving `amount` bytes. If we're getting ahead of
+    /// `max_rate`, waits until we aren't.
+    async fn throttle(&self, amount: u64) {
+        let max_rate = match self.max_rate {
+            Some(x) => x,
+            None => return,
+        };
+        let throttled_bytes = self.throttled_bytes.fetch_add(amount, AtomicOrdering::Relaxed) + amount;
+        let target_elapsed = Duration::from_secs_f64(throttled_bytes as f64 / max_rate as f64);
+        let elapsed = self.start_time.elapsed();
+        if target_elapsed > elapsed {
+            tokio::time::sleep(target_elapsed - elapsed).await;
+        }
+    }
+}
+
+/// Looks for a partial download left behind by an earlier, interrupted run.
+/// If there is one, returns a hasher that has already been fed its contents,
+/// along with the number of bytes it contains.
+fn find_partial_download(tmp_path: &Path, size: u64) -> Option<(lsx::sha256::BufSha256, u64)> {
+    let mut f = File::open(tmp_path).ok()?;
+    let mut hasher = lsx::sha256::BufSha256::new();
+    let mut partial_size = 0;
+    let mut buf = [0u8; 32768];
+    loop {
+        let red = match f.read(&mut buf[..]) {
+            Ok(0) => break,
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+        hasher.update(&buf[..red]);
+        partial_size += red as u64;
+    }
+    if partial_size == 0 || partial_size >= size { return None }
+    Some((hasher, partial_size))
+}
+
+/// Directories that macOS's System Integrity Protection won't let anyone
+/// write to. (`/usr/local` is the exception.)
+const SIP_PROTECTED_DIRS: &[&str] = &["/System", "/usr", "/bin", "/sbin"];
+
+/// If `x` is macOS's System Integrity Protection keeping us from writing to
+/// `path`, returns an explanation of that, suitable for the user.
+fn explain_sip(path: &Path, x: &std::io::Error) -> Option<String> {
+    if !cfg!(target_os="macos") || x.kind() != ErrorKind::PermissionDenied { return None }
+    if path.starts_with("/usr/local") { return None }
+    if !SIP_PROTECTED_DIRS.iter().any(|dir| path.starts_with(dir)) { return None }
+    Some(format!("macOS's System Integrity Protection won't let anything change files in this location, not even an updater. Move the application to /Applications, or another folder you can write to, then run the updater again.\nThe path was:\n{:?}", path))
+}
+
+/// Reserves space for a file we're about to write `size` bytes to, so that
+/// we find out now, rather than halfway through, if there isn't room. Only
+/// fails if there definitely isn't room.
+#[cfg(target_os="linux")]
+fn preallocate(f: &File, size: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use nix::errno::Errno;
+    if size == 0 { return Ok(()) }
+    match nix::fcntl::posix_fallocate(f.as_raw_fd(), 0, size as _) {
+        Ok(_) => Ok(()),
+        Err(Errno::ENOSPC) => Err(std::io::Error::from(Errno::ENOSPC)),
+        Err(Errno::EOPNOTSUPP) => Ok(()),
+        Err(x) => {
+            debug!("posix_fallocate failed: {}", x);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn preallocate(_f: &File, _size: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How many times to ask the user to close whatever has a file open.
+const IN_USE_RETRIES: u32 = 5;
+/// How long to wait after they say they have, before trying again.
+const IN_USE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Windows won't let us replace or delete a file that another process has
+/// open (e.g. a DLL that's loaded by a running copy of the application).
+/// Asks the user to close it, then tries `op` again, a few times.
+async fn retry_if_in_use<T>(gui: &Rc<RefCell<dyn Gui>>, path: &Path, mut op: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut attempts = 0;
+    loop {
+        match op() {
+            // ERROR_SHARING_VIOLATION
+            Err(x) if cfg!(windows) && x.raw_os_error() == Some(32) && attempts < IN_USE_RETRIES => {
+                attempts += 1;
+                debug!("{:?}: in use, attempt {} of {}", path, attempts, IN_USE_RETRIES);
+                if !gui.borrow_mut().do_warning("File in use", &format!("The file is in use by another process. Please close all instances of the application and click OK to retry.\nThe file is:\n{:?}", path), true) {
+                    return Err(x)
+                }
+                tokio::time::sleep(IN_USE_RETRY_DELAY).await;
+            },
+            x => return x,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(path: &Path) -> std::io::Result<()> {
+    debug!("{:?}: marked executable in the catalog, but that's informational only on this platform", path);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_symlink(path: &Path) -> std::io::Result<PathBuf> {
+    std::fs::read_link(path)
+}
+
+#[cfg(unix)]
+fn write_symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    // Make the link off to the side, then move it into place, same as with
+    // downloaded files.
+    let tmp_path = temp_path_for(path);
+    let _ = std::fs::remove_file(&tmp_path);
+    std::os::unix::fs::symlink(target, &tmp_path)?;
+    std::fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })
+}
+
+/// Where we write a symlink's target on platforms where we don't make real
+/// symlinks.
+#[cfg(not(unix))]
+fn symlink_stand_in_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lnk.txt");
+    path.with_file_name(name)
+}
+
+#[cfg(not(unix))]
+fn read_symlink(path: &Path) -> std::io::Result<PathBuf> {
+    std::fs::read_to_string(symlink_stand_in_path(path)).map(PathBuf::from)
+}
+
+#[cfg(not(unix))]
+fn write_symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    let stand_in = symlink_stand_in_path(path);
+    tracing::warn!("{:?}: can't make symlinks on this platform, writing the target to {:?} instead", path, stand_in);
+    std::fs::write(stand_in, target.to_string_lossy().as_bytes())
+}
+
+/// Puts a symlink entry in place. Called instead of downloading anything.
+fn place_symlink(gui: &Rc<RefCell<dyn Gui>>, backup: Option<&RefCell<Backup>>, cat: &Cat, target: &Path) -> Result<(),TupdateError> {
+    debug!("{:?}: linking to {:?}", &cat.dst_path, target);
+    if let Some(backup) = backup {
+        if let Err(x) = backup.borrow_mut().save(&cat.dst_path) {
+            gui.borrow_mut().do_error("Update failed", &format!("Couldn't back up one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", cat.dst_path, x));
+            return Err(TupdateError::IoError);
+        }
+    }
+    let _ = std::fs::create_dir_all(cat.dst_path.parent().unwrap());
+    if let Err(x) = write_symlink(target, &cat.dst_path) {
+        gui.borrow_mut().do_error("Update failed", &explain_sip(&cat.dst_path, &x).unwrap_or_else(|| format!("Couldn't create one of the links we need to update. The path was:\n{:?}\nand the error was:\n{}", cat.dst_path, x)));
+        return Err(TupdateError::IoError);
+    }
+    Ok(())
+}
+
+/// Writes one chunk of a download, and hashes it only if the write
+/// succeeded, so that the hash never covers data that isn't in the file.
+fn write_chunk<W: Write>(f: &mut W, hasher: &mut lsx::sha256::BufSha256, chunk: &[u8]) -> std::io::Result<()> {
+    f.write_all(chunk)?;
+    hasher.update(chunk);
+    Ok(())
+}
+
+/// How to go about downloading the files that need updating.
+#[derive(Clone, Copy, Debug)]
+struct DownloadSettings {
+    retry: RetryPolicy,
+    /// How long to wait for more data before giving up on a download.
+    read_timeout: Duration,
+    /// How many files to download at once.
+    jobs: usize,
+    /// Bytes per second, if limited.
+    max_rate: Option<u64>,
+    /// Bytes, if limited.
+    max_total_download: Option<u64>,
+    /// Whether to keep partial downloads around, and pick up where they left
+    /// off.
+    resume: bool,
+}
+
+async fn download_one(gui: &Rc<RefCell<dyn Gui>>, client: &reqwest::Client, settings: &DownloadSettings, backup: Option<&RefCell<Backup>>, cat: Cat, progress: &DownloadProgress) -> Result<(),TupdateError> {
+    let DownloadSettings { retry, read_timeout, resume, .. } = *settings;
+    if let CatKind::Symlink(target) = &cat.kind {
+        return place_symlink(gui, backup, &cat, target)
+    }
+    let tmp_path = temp_path_for(&cat.dst_path);
+    let partial = if resume { find_partial_download(&tmp_path, cat.size) } else { None };
+    let mut request = client.get(cat.src_url.clone());
+    if let Some((_, partial_size)) = partial.as_ref() {
+        debug!("{:?}: resuming from byte {}", &cat.dst_path, partial_size);
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", partial_size));
+    }
+    let mut response = match send_with_retries(retry, &cat.src_url, request).await {
+        Ok(x) if x.status() == 200 || (x.status() == 206 && partial.is_some()) => x,
+        Ok(x) => {
+            debug!("failed to download {}", &cat.src_url);
+            gui.borrow_mut().do_error_coded("Download failed", &format!("Error \"{}\" while trying to download an updated file.", x.status()), ERR_FILE_STATUS);
+            return Err(TupdateError::NetworkError);
+        },
+        Err(x) => {
+            debug!("failed to download {}", &cat.src_url);
+            gui.borrow_mut().do_error_coded("Download failed", &format!("Couldn't download an updated file. The error was:\n{}", describe_http_error(&x)), ERR_FILE_TRANSFER);
+            return Err(TupdateError::NetworkError);
+        },
+    };
+    // Catch a server that disagrees with the catalog before we spend any
+    // bandwidth on it.
+    let expected_length = match partial.as_ref() {
+        Some((_, partial_size)) if response.status() == 206 => cat.size - partial_size,
+        _ => cat.size,
+    };
+    if let Some(content_length) = response.content_length() {
+        if content_length != expected_length {
+            debug!("{}: Content-Length is {}, expected {}", &cat.src_url, content_length, expected_length);
+            gui.borrow_mut().do_error_coded("Download failed", &format!("Server claims file is {} bytes but catalog expects {} bytes. The file is:\n{}", content_length, expected_length, cat.src_url), ERR_FILE_LENGTH);
+            return Err(TupdateError::VerificationError);
+        }
+    }
+    info!("{:?}: downloading ({})", &cat.dst_path, format_bytes(cat.size));
+    progress.start_file(&cat.dst_path);
+    let _ = std::fs::create_dir_all(cat.dst_path.parent().unwrap());
+    let (f, mut file_recvd_bytes, mut file_hasher) = match partial {
+        Some((hasher, partial_size)) if response.status() == 206 => {
+            (OpenOptions::new().append(true).open(&tmp_path), partial_size, hasher)
+        },
+        partial => {
+            if partial.is_some() {
+                debug!("{:?}: server doesn't support resuming, starting over", &cat.dst_path);
             }
+            (File::create(&tmp_path), 0, lsx::sha256::BufSha256::new())
+        },
+    };
+    let mut f = match f {
+        Ok(x) => x,
+        Err(x) => {
+            gui.borrow_mut().do_error("Update failed", &explain_sip(&tmp_path, &x).unwrap_or_else(|| format!("Couldn't open one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", tmp_path, x)));
+            return Err(TupdateError::IoError);
+        },
+    };
+    if file_recvd_bytes == 0 {
+        if let Err(x) = preallocate(&f, cat.size) {
+            drop(f);
+            let _ = std::fs::remove_file(&tmp_path);
+            gui.borrow_mut().do_error("Update failed", &format!("Couldn't make room for one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", tmp_path, x));
+            return Err(TupdateError::IoError);
         }
-        let sum = file_hasher.finish(&[]);
-        if sum != cat.checksum || file_recvd_bytes != cat.size {
-            gui.borrow_mut().do_error("Update failed", &format!("One of the downloads was corrupted. Try running the updater again."));
-            return Err(());
+    }
+    progress.add_bytes(gui, file_recvd_bytes);
+    while file_recvd_bytes <= cat.size {
+        let chunk = match tokio::time::timeout(read_timeout, response.chunk()).await {
+            Ok(x) => x.map_err(|x| describe_http_error(&x)),
+            Err(_) => Err("connection timed out".to_string()),
+        };
+        match chunk {
+            Err(x) => {
+                drop(f);
+                // Leave what we got so far for the next run to pick up.
+                if !resume {
+                    let _ = std::fs::remove_file(&tmp_path);
+                }
+                gui.borrow_mut().do_error_coded("Download failed", &format!("Error while downloading an updated file. The error was:\n{}", x), ERR_FILE_TRANSFER);
+                return Err(TupdateError::NetworkError);
+            },
+            Ok(None) => break,
+            Ok(Some(x)) => {
+                if let Err(x) = write_chunk(&mut f, &mut file_hasher, &x[..]) {
+                    drop(f);
+                    let _ = std::fs::remove_file(&tmp_path);
+                    gui.borrow_mut().do_error("Update failed", &format!("Couldn't write to one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", tmp_path, x));
+                    return Err(TupdateError::IoError);
+                }
+                file_recvd_bytes += x.len() as u64;
+                progress.add_bytes(gui, x.len() as u64);
+                if file_recvd_bytes > cat.size {
+                    drop(f);
+                    let _ = std::fs::remove_file(&tmp_path);
+                    gui.borrow_mut().do_error_coded("Download failed", &format!("Server sent more data than expected for {}. The catalog says it should be {}.", cat.src_url, format_bytes(cat.size)), ERR_FILE_OVERRUN);
+                    return Err(TupdateError::VerificationError);
+                }
+                if progress.exceeds_max_total(x.len() as u64) {
+                    drop(f);
+                    // Resuming it would only run into the same limit.
+                    let _ = std::fs::remove_file(&tmp_path);
+                    gui.borrow_mut().do_error("Download failed", "The update has downloaded more than the limit given with --max-total-download.");
+                    return Err(TupdateError::LimitExceeded);
+                }
+                progress.throttle(x.len() as u64).await;
+            },
+        }
+        if cancel_requested() {
+            drop(f);
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(TupdateError::Cancelled);
+        }
+    }
+    // Make sure everything actually made it to the file before we let it
+    // replace the original.
+    let flushed = f.sync_all();
+    drop(f);
+    if let Err(x) = flushed {
+        let _ = std::fs::remove_file(&tmp_path);
+        gui.borrow_mut().do_error("Update failed", &format!("Couldn't write to one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", tmp_path, x));
+        return Err(TupdateError::IoError);
+    }
+    let sum = file_hasher.finish(&[]);
+    if sum != cat.checksum || file_recvd_bytes != cat.size {
+        let _ = std::fs::remove_file(&tmp_path);
+        gui.borrow_mut().do_error("Update failed", &format!("One of the downloads was corrupted. Try running the updater again."));
+        return Err(TupdateError::VerificationError);
+    }
+    if cat.executable {
+        if let Err(x) = make_executable(&tmp_path) {
+            let _ = std::fs::remove_file(&tmp_path);
+            gui.borrow_mut().do_error("Update failed", &format!("Couldn't make one of the updated files executable. The path was:\n{:?}\nand the error was:\n{}", tmp_path, x));
+            return Err(TupdateError::IoError);
+        }
+    }
+    if let Some(backup) = backup {
+        if let Err(x) = backup.borrow_mut().save(&cat.dst_path) {
+            let _ = std::fs::remove_file(&tmp_path);
+            gui.borrow_mut().do_error("Update failed", &format!("Couldn't back up one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", cat.dst_path, x));
+            return Err(TupdateError::IoError);
+        }
+    }
+    if let Err(x) = retry_if_in_use(gui, &cat.dst_path, || std::fs::rename(&tmp_path, &cat.dst_path)).await {
+        let _ = std::fs::remove_file(&tmp_path);
+        gui.borrow_mut().do_error("Update failed", &explain_sip(&cat.dst_path, &x).unwrap_or_else(|| format!("Couldn't replace one of the files we need to update. The path was:\n{:?}\nand the error was:\n{}", cat.dst_path, x)));
+        return Err(TupdateError::IoError);
+    }
+    if let Some(mtime) = cat.mtime {
+        // The file itself is fine, so this isn't worth failing the update over.
+        if let Err(x) = filetime::set_file_mtime(&cat.dst_path, filetime::FileTime::from_system_time(mtime)) {
+            debug!("{:?}: couldn't set modification time: {}", &cat.dst_path, x);
         }
     }
+    info!("{:?}: done ({})", &cat.dst_path, format_bytes(cat.size));
     Ok(())
 }
 
-fn perform_deletions(gui: &Rc<RefCell<dyn Gui>>, _verbose: bool, all_deletions: Vec<PathBuf>) -> Result<(),()> {
+async fn perform_downloads(gui: &Rc<RefCell<dyn Gui>>, client: &mut reqwest::Client, settings: &DownloadSettings, backup: Option<&RefCell<Backup>>, all_cats: Vec<Cat>) -> Result<u64,TupdateError> {
+    let DownloadSettings { jobs, max_rate, max_total_download, .. } = *settings;
+    // A bogus catalog could claim sizes that add up to more than a u64 holds.
+    let total_cat_bytes = all_cats.iter().fold(0u64, |a,x| a.saturating_add(if x.needs_download { x.size } else { 0 }));
+    if let Some(max_total_download) = max_total_download {
+        if total_cat_bytes > max_total_download {
+            gui.borrow_mut().do_error("Update too large", &format!("The update needs to download {}, but --max-total-download only allows {}.", format_bytes(total_cat_bytes), format_bytes(max_total_download)));
+            return Err(TupdateError::LimitExceeded);
+        }
+    }
+    let progress = DownloadProgress {
+        start_time: Instant::now(),
+        total_cat_bytes,
+        total_recvd_bytes: AtomicU64::new(0),
+        patience: RefCell::new(Patience::new()),
+        rate_samples: RefCell::new(VecDeque::with_capacity(RATE_SAMPLES)),
+        max_rate,
+        throttled_bytes: AtomicU64::new(0),
+        max_total_download,
+        transferred_bytes: AtomicU64::new(0),
+        log_patience: RefCell::new(Patience::with_interval(STILL_DOWNLOADING_INTERVAL)),
+        current_file: RefCell::new(String::new()),
+    };
+    progress.add_bytes(gui, 0);
+    let client = &*client;
+    let progress = &progress;
+    // All of the downloads are driven from this one task, so they can share
+    // the GUI; `jobs` only limits how many of them are in flight at once.
+    let mut downloads = futures_util::stream::iter(all_cats.into_iter().filter(|cat| cat.needs_download))
+        .map(|cat| download_one(gui, client, settings, backup, cat, progress))
+        .buffer_unordered(jobs.max(1));
+    while let Some(result) = downloads.next().await {
+        if let Err(x) = result {
+            if x == TupdateError::Cancelled {
+                gui.borrow_mut().do_message("Update cancelled", "The update was cancelled before it finished.");
+            }
+            return Err(x);
+        }
+    }
+    Ok(progress.transferred_bytes.load(AtomicOrdering::Relaxed))
+}
+
+async fn perform_deletions(gui: &Rc<RefCell<dyn Gui>>, backup: Option<&RefCell<Backup>>, basedirs: &[PathBuf], all_deletions: Vec<PathBuf>) -> Result<usize,TupdateError> {
     let num_deletions = all_deletions.len();
+    let mut num_deleted = 0;
+    let mut emptied_dirs = vec![];
     for (n, deletion) in all_deletions.into_iter().enumerate() {
+        // Every directory between this and its basedir might be left empty.
+        if let Some(basedir) = basedirs.iter().find(|x| deletion.starts_with(x)) {
+            let mut parent = deletion.parent();
+            while let Some(dir) = parent {
+                if dir == basedir || !dir.starts_with(basedir) { break }
+                emptied_dirs.push(dir.to_path_buf());
+                parent = dir.parent();
+            }
+        }
         gui.borrow_mut().set_progress("Deleting obsolete files...", "", Some(n as f32 / num_deletions as f32));
         let is_dir = match std::fs::metadata(&deletion) {
             Ok(x) => x.is_dir(),
             Err(x) if x.kind() == ErrorKind::NotFound => continue,
             Err(x) => {
                 gui.borrow_mut().do_error("Error during final deletion", &format!("Unable to get the metadata for {:?}: {}", &deletion, x));
-                return Err(())
+                return Err(TupdateError::IoError)
             }
         };
-        let result = if is_dir { std::fs::remove_dir_all(&deletion) } else { std::fs::remove_file(&deletion) };
+        if let Some(backup) = backup {
+            if let Err(x) = backup.borrow_mut().save(&deletion) {
+                gui.borrow_mut().do_error("Error during final deletion", &format!("Unable to back up {:?}: {}", &deletion, x));
+                return Err(TupdateError::IoError)
+            }
+        }
+        let result = retry_if_in_use(gui, &deletion, || if is_dir { std::fs::remove_dir_all(&deletion) } else { std::fs::remove_file(&deletion) }).await;
         if let Err(x) = result {
-            gui.borrow_mut().do_error("Error during final deletion", &format!("Unable to delete {:?}: {}", &deletion, x));
-            return Err(())
+            gui.borrow_mut().do_error("Error during final deletion", &explain_sip(&deletion, &x).unwrap_or_else(|| format!("Unable to delete {:?}: {}", &deletion, x)));
+            return Err(TupdateError::IoError)
         }
+        num_deleted += 1;
+    }
+    // Deepest first, so that a directory's subdirectories are already gone
+    // by the time we get to it.
+    emptied_dirs.sort_by(|a, b| b.components().count().cmp(&a.components().count()).then_with(|| a.cmp(b)));
+    emptied_dirs.dedup();
+    for dir in emptied_dirs.iter() {
+        // Fails harmlessly if the directory isn't empty.
+        if std::fs::remove_dir(dir).is_ok() {
+            info!("removed empty directory: {:?}", dir);
+        }
+    }
+    Ok(num_deleted)
+}
+
+/// Runs the `POST_INSTALL=` command, in the config file's directory. If it
+/// fails, asks the user whether to count the update as a failure. Returns
+/// true if we should carry on as if it succeeded.
+fn run_post_install(gui: &Rc<RefCell<dyn Gui>>, command: &str, dir: Option<&Path>) -> bool {
+    info!("Running post-install command: {}", command);
+    gui.borrow_mut().set_progress("Running post-install script...", "", None);
+    let mut process = if cfg!(windows) {
+        let mut x = std::process::Command::new("cmd");
+        x.arg("/C").arg(command);
+        x
+    }
+    else {
+        let mut x = std::process::Command::new("sh");
+        x.arg("-c").arg(command);
+        x
+    };
+    if let Some(dir) = dir {
+        process.current_dir(dir);
+    }
+    let problem = match process.status() {
+        Ok(x) if x.success() => return true,
+        Ok(x) => format!("The post-install script failed ({}).", x),
+        Err(x) => format!("Couldn't run the post-install script. The error was:\n{}", x),
+    };
+    debug!("{}", problem);
+    gui.borrow_mut().do_warning("Post-install script failed", &format!("{}\nThe update itself succeeded. Press OK to carry on anyway, or Cancel to treat the update as failed.", problem), true)
+}
+
+/// What a successful update did, for the user's benefit.
+struct UpdateStats {
+    files_downloaded: usize,
+    bytes_downloaded: u64,
+    files_deleted: usize,
+    elapsed_seconds: f64,
+}
+
+impl std::fmt::Display for UpdateStats {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Updated {} files ({}), deleted {} files in {:.1} seconds.", self.files_downloaded, format_bytes(self.bytes_downloaded), self.files_deleted, self.elapsed_seconds)
+    }
+}
+
+/// Called after a failure partway through applying an update. If we made
+/// backups, offers to put them back.
+fn offer_restore(gui: &Rc<RefCell<dyn Gui>>, backup: Option<RefCell<Backup>>) {
+    let backup = match backup {
+        Some(x) => x.into_inner(),
+        None => return,
+    };
+    if backup.is_empty() { return }
+    if !gui.borrow_mut().do_warning("Restore backed-up files?", "The update failed partway through. Press OK to put back the files it already changed, or Cancel to leave them as they are.", true) {
+        return
+    }
+    gui.borrow_mut().set_progress("Restoring backed-up files...", "", None);
+    match backup.restore() {
+        Ok(_) => gui.borrow_mut().do_message("Files restored", "All of the files the update changed have been put back."),
+        Err((path, x)) => gui.borrow_mut().do_error("Restore failed", &format!("Unable to restore {:?}: {}", path, x)),
+    }
+}
+
+/// Called instead of applying the update when `--verify` is given. Reports
+/// which files don't match the catalog.
+fn report_verification(gui: &Rc<RefCell<dyn Gui>>, all_cats: &[Cat]) -> ExitCode {
+    let mut num_mismatched = 0;
+    for cat in all_cats.iter() {
+        if let Some(mismatch) = cat.mismatch {
+            gui.borrow_mut().verbose(&format!("{:?}: {}", cat.dst_path, mismatch));
+            num_mismatched += 1;
+        }
+    }
+    if num_mismatched == 0 {
+        gui.borrow_mut().do_message("Verification complete", "All files are up to date.");
+        ExitCode::SUCCESS
+    }
+    else {
+        let message = if num_mismatched == 1 { "1 file is out of date.".to_string() }
+        else { format!("{} files are out of date.", num_mismatched) };
+        gui.borrow_mut().do_message("Verification complete", &message);
+        ExitCode::FAILURE
     }
-    Ok(())
 }
 
-async fn real_main(gui: Rc<RefCell<dyn Gui>>, verbose: bool, target_url: Option<Url>) -> ExitCode {
-    let target_url = match find_target_url(&gui, verbose, target_url) {
+/// Called instead of applying the update when `--list` is given. This output
+/// is for scripts, so it goes straight to stdout instead of through the GUI.
+fn report_list(all_cats: &[Cat]) -> ExitCode {
+    let mut stdout = std::io::stdout().lock();
+    for cat in all_cats.iter() {
+        if writeln!(stdout, "{}\t{}", cat.dst_path.display(), cat.size).is_err() {
+            return ExitCode::FAILURE
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Called instead of applying the update when `--check` is given. Reports
+/// how many changes are pending.
+fn report_check(gui: &Rc<RefCell<dyn Gui>>, all_cats: &[Cat], all_deletions: &[PathBuf]) -> ExitCode {
+    let num_changes = all_cats.iter().filter(|x| x.needs_download).count() + all_deletions.len();
+    if num_changes == 0 {
+        gui.borrow_mut().do_message("No updates available", "All files are already up to date.");
+        ExitCode::SUCCESS
+    }
+    else {
+        let message = if num_changes == 1 { "1 change is pending.".to_string() }
+        else { format!("{} changes are pending.", num_changes) };
+        gui.borrow_mut().do_message("Updates available", &message);
+        ExitCode::FAILURE
+    }
+}
+
+/// Called instead of actually applying the update when `--dry-run` is given.
+/// Reports what would have been done.
+fn report_dry_run(gui: &Rc<RefCell<dyn Gui>>, all_cats: &[Cat], all_deletions: &[PathBuf]) -> ExitCode {
+    let mut report = String::new();
+    for cat in all_cats.iter().filter(|x| x.needs_download) {
+        if report.is_empty() { report.push_str("Would download:\n") }
+        report.push_str(&format!("    {:?} <- {} ({} bytes)\n", cat.dst_path, cat.src_url, cat.size));
+    }
+    if !all_deletions.is_empty() {
+        report.push_str("Would delete:\n");
+        for deletion in all_deletions.iter() {
+            report.push_str(&format!("    {:?}\n", deletion));
+        }
+    }
+    if report.is_empty() {
+        gui.borrow_mut().do_message("Dry run complete", "All files are already up to date.");
+        ExitCode::SUCCESS
+    }
+    else {
+        gui.borrow_mut().do_message("Dry run complete", report.trim_end());
+        ExitCode::FAILURE
+    }
+}
+
+async fn real_main(gui: Rc<RefCell<dyn Gui>>, invocation: Invocation) -> ExitCode {
+    let start_time = Instant::now();
+    if init_logging(&gui, invocation.verbose, invocation.log_file.as_deref()).is_err() {
+        return ExitCode::FAILURE
+    }
+    let (target_urls, config) = match find_target_url(&gui, invocation.target_url) {
         Ok(x) => x,
         Err(_) => return ExitCode::FAILURE,
     };
+    if let Some(title) = config.title.as_ref() {
+        gui.borrow_mut().set_title(title);
+    }
+    let retry = RetryPolicy { retries: invocation.retries.or(config.retries).unwrap_or(DEFAULT_RETRIES), delay: invocation.retry_delay };
+    let user_agent = match invocation.user_agent.or(config.user_agent).filter(|x| !x.is_empty()) {
+        // Anything after a line break would become a header of its own.
+        Some(x) if x.contains(&['\r', '\n'][..]) => {
+            gui.borrow_mut().do_error("Invalid user agent", "The user agent may not contain line breaks.");
+            return ExitCode::FAILURE
+        },
+        Some(x) => x,
+        None => DEFAULT_USER_AGENT.to_string(),
+    };
+    info!("User agent: {}", user_agent);
+    let authorization = match load_token(&gui, invocation.token.or(config.token), config.token_file.as_deref()) {
+        Ok(x) => x,
+        Err(_) => return ExitCode::FAILURE,
+    };
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(authorization) = authorization {
+        for url in target_urls.iter().filter(|x| x.scheme() != "https") {
+            tracing::warn!("{}: not HTTPS, so the token will be sent in plain text!", url);
+        }
+        headers.insert(reqwest::header::AUTHORIZATION, authorization);
+    }
     let mut client = reqwest::Client::builder()
-        .user_agent(concat!("TUpdate/", env!("CARGO_PKG_VERSION")))
-        //.add_root_certificate(...)
-        .build().unwrap();
-    let (mut all_cats, mut all_deletions) = match determine_tasks(&gui, verbose, &mut client, &target_url).await {
+        .user_agent(user_agent)
+        .default_headers(headers)
+        .connect_timeout(Duration::from_secs_f64(invocation.connect_timeout.or(config.connect_timeout).unwrap_or(DEFAULT_CONNECT_TIMEOUT)));
+    if let Some(timeout) = invocation.timeout {
+        client = client.timeout(Duration::from_secs_f64(timeout));
+    }
+    let client = match configure_proxy(&gui, invocation.proxy.as_deref(), client) {
         Ok(x) => x,
         Err(_) => return ExitCode::FAILURE,
     };
-    if find_cat_statuses(&gui, verbose, &mut all_cats).is_err() {
-        return ExitCode::FAILURE
+    let client = match invocation.ca_cert.or(config.ca_cert) {
+        Some(path) => {
+            info!("Trusting CA certificate: {:?}", path);
+            match load_certificate(&gui, &path) {
+                Ok(x) => client.add_root_certificate(x),
+                Err(_) => return ExitCode::FAILURE,
+            }
+        },
+        None => client,
+    };
+    let client = match load_identity(&gui, invocation.client_cert.as_deref(), invocation.client_key.as_deref(), invocation.client_pfx.as_deref(), invocation.client_pfx_password.as_deref()) {
+        Ok(Some(x)) => client.identity(x),
+        Ok(None) => client,
+        Err(_) => return ExitCode::FAILURE,
+    };
+    let mut client = client.build().unwrap();
+    let public_key = match invocation.public_key.or(config.public_key) {
+        Some(x) => match load_public_key(&gui, &x) {
+            Ok(x) => Some(x),
+            Err(_) => return ExitCode::FAILURE,
+        },
+        None => None,
+    };
+    let cache = if invocation.no_cache { None } else { CatalogCache::new() };
+    let channel = invocation.channel.or(config.channel).unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+    // Relative to where we were run from, not to wherever the index decides
+    // to look.
+    let basedir_override = invocation.basedir.as_ref().map(|x| match std::env::current_dir() {
+        Ok(cwd) => cwd.join(x),
+        Err(_) => x.clone(),
+    });
+    let catalog_settings = CatalogSettings {
+        retry,
+        catalog_jobs: invocation.catalog_jobs,
+        lua_limit: invocation.lua_limit,
+        basedir_override: basedir_override.as_deref(),
+        channel: &channel,
+        allowed_hosts: &invocation.allow_host,
+        cache: cache.as_ref(),
+        public_key: public_key.as_ref(),
+    };
+    let (mut all_cats, mut all_deletions, basedirs) = match determine_tasks(&gui, &mut client, &catalog_settings, &target_urls).await {
+        Ok(x) => x,
+        Err(x) => return x.into(),
+    };
+    if invocation.list {
+        return report_list(&all_cats)
     }
-    trim_deletions(&gui, verbose, &mut all_cats, &mut all_deletions);
-    if perform_downloads(&gui, verbose, &mut client, all_cats).await.is_err() {
-        return ExitCode::FAILURE
+    if invocation.verify {
+        if let Err(x) = find_cat_statuses(&gui, invocation.repair, &mut all_cats) {
+            return x.into()
+        }
+        return report_verification(&gui, &all_cats)
     }
-    if perform_deletions(&gui, verbose, all_deletions).is_err() {
-        return ExitCode::FAILURE
+    let _lock = match basedirs.first() {
+        Some(basedir) if !invocation.dry_run && !invocation.check => match UpdateLock::take(&gui, basedir) {
+            Ok(x) => Some(x),
+            Err(_) => return ExitCode::FAILURE,
+        },
+        _ => None,
+    };
+    if let Err(x) = find_cat_statuses(&gui, invocation.repair, &mut all_cats) {
+        return x.into()
+    }
+    trim_deletions(&mut all_cats, &mut all_deletions);
+    if invocation.check {
+        return report_check(&gui, &all_cats, &all_deletions)
+    }
+    if invocation.dry_run {
+        return report_dry_run(&gui, &all_cats, &all_deletions)
+    }
+    if !check_disk_space(&gui, &all_cats) {
+        return TupdateError::Cancelled.into()
+    }
+    let backup = invocation.backup_dir.map(|x| RefCell::new(Backup::new(x, basedirs.clone())));
+    let files_downloaded = all_cats.iter().filter(|x| x.needs_download).count();
+    let download_settings = DownloadSettings {
+        retry,
+        read_timeout: Duration::from_secs_f64(invocation.read_timeout),
+        jobs: invocation.jobs,
+        max_rate: invocation.max_rate.or(config.max_rate),
+        max_total_download: invocation.max_total_download,
+        resume: !invocation.no_resume,
+    };
+    let bytes_downloaded = match perform_downloads(&gui, &mut client, &download_settings, backup.as_ref(), all_cats).await {
+        Ok(x) => x,
+        Err(x) => {
+            offer_restore(&gui, backup);
+            return x.into()
+        },
+    };
+    let files_deleted = match perform_deletions(&gui, backup.as_ref(), &basedirs, all_deletions).await {
+        Ok(x) => x,
+        Err(x) => {
+            offer_restore(&gui, backup);
+            return x.into()
+        },
+    };
+    if let Some(command) = config.post_install.as_ref() {
+        if (files_downloaded > 0 || files_deleted > 0) && !run_post_install(&gui, command, config.dir.as_deref()) {
+            return ExitCode::FAILURE
+        }
     }
-    gui.borrow_mut().do_message("Update complete", "All files are now up to date.");
+    let stats = UpdateStats { files_downloaded, bytes_downloaded, files_deleted, elapsed_seconds: start_time.elapsed().as_secs_f64() };
+    info!("{}", stats);
+    gui.borrow_mut().do_message("Update complete", &format!("All files are now up to date.\n{}", stats));
     ExitCode::SUCCESS
 }
 
 // hack to prevent Liso from being dropped inside the tokio runtime
 fn main() -> ExitCode {
-    let Invocation { gui: target_gui, verbose, target_url, pause } = Invocation::parse();
-    run_gui(target_gui, pause, move |gui| {
+    let invocation = Invocation::parse();
+    run_gui(invocation.gui.clone(), invocation.pause, invocation.yes, invocation.input_timeout.map(|x| Duration::from_secs_f64(x.max(0.0))), invocation.json, invocation.batch_errors_to_stdout, move |gui| {
         let rt = tokio::runtime::Runtime::new().unwrap();
         let gui_clone = gui.clone();
         let ret = rt.block_on(async move {
-            real_main(gui_clone, verbose, target_url).await
+            real_main(gui_clone, invocation).await
         });
+        finish_logging();
         drop(rt);
         drop(gui);
         ret
     })
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gui::mock::{MockGui, MockGuiEvent};
+
+    fn entry(path: &[u8], size: u64, extension: &[u8]) -> Vec<u8> {
+        let mut ret = path.to_vec();
+        ret.push(b'\n');
+        ret.extend_from_slice(&[0xAB; 32]);
+        ret.extend_from_slice(&size.to_be_bytes());
+        ret.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+        ret.extend_from_slice(extension);
+        ret
+    }
+
+    fn parse(bytes: &[u8]) -> Result<(Cat, &[u8]), ()> {
+        let base_url = Url::parse("https://example.com/files/").unwrap();
+        Cat::try_parse(bytes, &base_url, Path::new("/base"))
+    }
+
+    #[test]
+    fn parses_minimal_entry() {
+        let bytes = entry(b"dir/file.dat", 1234, &[]);
+        let (cat, rest) = parse(&bytes).unwrap();
+        assert_eq!(cat.src_url.as_str(), "https://example.com/files/dir/file.dat");
+        assert_eq!(cat.dst_path, Path::new("/base/dir/file.dat"));
+        assert_eq!(cat.checksum, [0xAB; 32]);
+        assert_eq!(cat.size, 1234);
+        assert!(!cat.executable);
+        assert!(matches!(cat.kind, CatKind::File));
+        assert!(cat.mtime.is_none());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn rejects_empty() {
+        assert!(parse(&[]).is_err());
+    }
+
+    #[test]
+    fn rejects_hidden() {
+        assert!(parse(&entry(b"/.hidden", 0, &[])).is_err());
+    }
+
+    #[test]
+    fn rejects_parent_dir() {
+        assert!(parse(&entry(b"..", 0, &[])).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_checksum() {
+        let bytes = entry(b"file.dat", 0, &[]);
+        assert!(parse(&bytes[..20]).is_err());
+        assert!(parse(&bytes[..bytes.len()-1]).is_err());
+    }
+
+    #[test]
+    fn rejects_overlong_extension() {
+        let mut bytes = entry(b"file.dat", 0, &[1, 2, 3]);
+        bytes.pop();
+        assert!(parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_non_utf8_path() {
+        assert!(parse(&entry(b"file\xFF.dat", 0, &[])).is_err());
+    }
+
+    #[test]
+    fn parses_extension_and_leaves_remainder() {
+        let mut extension = vec![1];
+        extension.extend_from_slice(&1_000_000_000u64.to_be_bytes());
+        let mut bytes = entry(b"bin/tool", 42, &extension);
+        let next = entry(b"other", 0, &[]);
+        bytes.extend_from_slice(&next);
+        let (cat, rest) = parse(&bytes).unwrap();
+        assert!(cat.executable);
+        assert_eq!(cat.size, 42);
+        assert_eq!(cat.mtime, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1)));
+        assert_eq!(rest.len(), next.len());
+        assert_eq!(rest, &next[..]);
+    }
+
+    fn symlink_entry(path: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut extension = vec![2];
+        extension.extend_from_slice(target);
+        entry(path, target.len() as u64, &extension)
+    }
+
+    fn parse_all(bytes: &[u8]) -> Vec<Cat> {
+        let mut cats = vec![];
+        let mut next = bytes;
+        while !next.is_empty() {
+            let (cat, rem) = parse(next).unwrap();
+            cats.push(cat);
+            next = rem;
+        }
+        cats
+    }
+
+    #[test]
+    fn rejects_chained_symlink_escape() {
+        let mut bytes = symlink_entry(b"a/t", b"..");
+        bytes.extend_from_slice(&symlink_entry(b"a/u", b"t/.."));
+        bytes.extend_from_slice(&entry(b"a/u/x", 5, &[]));
+        // Each entry passes on its own...
+        let cats = parse_all(&bytes);
+        assert_eq!(cats.len(), 3);
+        // ...but not together.
+        assert_eq!(find_chained_symlink(&cats).unwrap().dst_path, Path::new("/base/a/u"));
+        // The file is caught too, on its own account.
+        let cats: Vec<Cat> = cats.into_iter().filter(|x| x.dst_path != Path::new("/base/a/t")).collect();
+        assert_eq!(find_chained_symlink(&cats).unwrap().dst_path, Path::new("/base/a/u/x"));
+    }
+
+    #[test]
+    fn allows_symlink_to_symlink() {
+        let mut bytes = symlink_entry(b"lib/libfoo.so", b"libfoo.so.1");
+        bytes.extend_from_slice(&symlink_entry(b"lib/libfoo.so.1", b"libfoo.so.1.2"));
+        bytes.extend_from_slice(&entry(b"lib/libfoo.so.1.2", 5, &[]));
+        assert!(find_chained_symlink(&parse_all(&bytes)).is_none());
+    }
+
+    #[test]
+    fn rejects_file_under_symlink() {
+        let mut bytes = symlink_entry(b"a/t", b"..");
+        bytes.extend_from_slice(&entry(b"a/t/x", 5, &[]));
+        assert_eq!(find_chained_symlink(&parse_all(&bytes)).unwrap().dst_path, Path::new("/base/a/t/x"));
+    }
+
+    fn mock_gui(should_cancel: bool) -> (Rc<RefCell<MockGui>>, Rc<RefCell<dyn Gui>>) {
+        let mock = Rc::new(RefCell::new(MockGui::new(should_cancel)));
+        let gui: Rc<RefCell<dyn Gui>> = mock.clone();
+        (mock, gui)
+    }
+
+    #[test]
+    fn bad_token_is_an_error() {
+        let (mock, gui) = mock_gui(false);
+        assert!(load_token(&gui, Some("line\nbreak".to_string()), None).is_err());
+        assert!(matches!(&mock.borrow().events[..], [MockGuiEvent::Error { title, .. }] if title == "Invalid token"));
+    }
+
+    #[test]
+    fn bad_public_key_is_an_error() {
+        let (mock, gui) = mock_gui(false);
+        assert!(load_public_key(&gui, "not a key").is_err());
+        assert!(matches!(&mock.borrow().events[..], [MockGuiEvent::Error { title, .. }] if title == "Invalid public key"));
+    }
+
+    #[test]
+    fn check_with_nothing_to_do() {
+        let (mock, gui) = mock_gui(false);
+        assert_eq!(report_check(&gui, &[], &[]), ExitCode::SUCCESS);
+        assert_eq!(mock.borrow().events, [MockGuiEvent::Message {
+            title: "No updates available".to_string(),
+            message: "All files are already up to date.".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn verification_reports_mismatches() {
+        let (mock, gui) = mock_gui(false);
+        let bytes = entry(b"file.dat", 0, &[]);
+        let (mut cat, _) = parse(&bytes).unwrap();
+        cat.mismatch = Some(Mismatch::Checksum);
+        assert_eq!(report_verification(&gui, &[cat]), ExitCode::FAILURE);
+        let mock = mock.borrow();
+        assert!(matches!(&mock.events[0], MockGuiEvent::Verbose(x) if x.ends_with("checksum mismatch")));
+        assert!(matches!(&mock.events[1], MockGuiEvent::Message { message, .. } if message == "1 file is out of date."));
+        assert_eq!(mock.events.len(), 2);
+    }
+
+    #[test]
+    fn failed_post_install_can_be_cancelled() {
+        for should_cancel in [false, true] {
+            let (mock, gui) = mock_gui(should_cancel);
+            assert_eq!(run_post_install(&gui, "exit 1", None), !should_cancel);
+            let mock = mock.borrow();
+            assert!(matches!(&mock.events[0], MockGuiEvent::Progress { task, progress: None, .. } if task == "Running post-install script..."));
+            assert!(matches!(&mock.events[1], MockGuiEvent::Warning { can_cancel: true, .. }));
+        }
+    }
+
+    /// Accepts `limit` bytes, then fails every write after that.
+    struct FailingWriter {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let room = self.limit - self.written.len();
+            if room == 0 {
+                return Err(std::io::Error::other("disk full"))
+            }
+            let n = buf.len().min(room);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn write_failure_midway_is_not_hashed() {
+        let mut f = FailingWriter { written: vec![], limit: 8 };
+        let mut hasher = lsx::sha256::BufSha256::new();
+        assert!(write_chunk(&mut f, &mut hasher, b"hello ").is_ok());
+        // Only part of this one makes it to the "file".
+        assert!(write_chunk(&mut f, &mut hasher, b"world").is_err());
+        assert_eq!(f.written, b"hello wo");
+        assert_eq!(hasher.finish(&[]), lsx::sha256::hash(b"hello "));
+    }
+}
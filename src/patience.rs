@@ -5,11 +5,25 @@ const UPDATE_INTERVAL: Duration = Duration::new(0, 200000000); // 5Hz
 /// Keeps track of time, only updates if some time has passed since last time
 pub struct Patience {
     last_time: Option<Instant>,
+    interval: Duration,
 }
 
 impl Patience {
     pub fn new() -> Patience {
-        Patience { last_time: None }
+        Patience::with_interval(UPDATE_INTERVAL)
+    }
+    pub fn with_interval(interval: Duration) -> Patience {
+        Patience { last_time: None, interval }
+    }
+    /// For when the GUI must be updated no matter what, e.g. at the start of
+    /// a phase. Always returns true, and starts the wait over.
+    pub fn force(&mut self) -> bool {
+        self.last_time = Some(Instant::now());
+        true
+    }
+    /// How long it's been since we last said to update, if we ever have.
+    pub fn elapsed_since_last(&self) -> Option<Duration> {
+        self.last_time.map(|x| Instant::now().saturating_duration_since(x))
     }
     pub fn have_been_patient(&mut self) -> bool {
         let now = Instant::now();
@@ -26,13 +40,13 @@ impl Patience {
                 }
                 else {
                     let diff = now - last_time;
-                    if diff >= UPDATE_INTERVAL * 5 {
+                    if diff >= self.interval * 5 {
                         self.last_time = Some(now);
                         true
                     }
-                    else if diff >= UPDATE_INTERVAL {
+                    else if diff >= self.interval {
                         while last_time < now {
-                            last_time += UPDATE_INTERVAL;
+                            last_time += self.interval;
                         }
                         self.last_time = Some(last_time);
                         true
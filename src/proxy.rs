@@ -0,0 +1,82 @@
+use super::*;
+
+/// Returns the value of the first of the given environment variables that is
+/// set and non-empty.
+fn first_env_var(names: &[&str]) -> Option<(String, String)> {
+    names.iter().find_map(|name| {
+        match std::env::var(name) {
+            Ok(x) if !x.is_empty() => Some((name.to_string(), x)),
+            _ => None,
+        }
+    })
+}
+
+fn proxy_from_env(gui: &Rc<RefCell<dyn Gui>>, names: &[&str]) -> Result<Option<Url>, ()> {
+    let (name, value) = match first_env_var(names) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    match Url::parse(&value) {
+        Ok(x) => Ok(Some(x)),
+        Err(x) => {
+            gui.borrow_mut().do_error("Invalid proxy", &format!("The {} environment variable does not contain a valid proxy URL. The error was:\n{}", name, x));
+            Err(())
+        },
+    }
+}
+
+/// Returns true if `host` is covered by an entry from `NO_PROXY`.
+fn no_proxy_matches(host: &str, entry: &str) -> bool {
+    if entry == "*" { return true }
+    let entry = entry.trim_start_matches('.');
+    host == entry || (host.ends_with(entry) && host[..host.len() - entry.len()].ends_with('.'))
+}
+
+/// Tells the client builder which proxy to use: the one given on the command
+/// line if there was one, otherwise whatever `HTTP_PROXY`, `HTTPS_PROXY`, and
+/// `NO_PROXY` say.
+pub fn configure_proxy(gui: &Rc<RefCell<dyn Gui>>, proxy: Option<&str>, client: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder, ()> {
+    if let Some(proxy) = proxy {
+        return match reqwest::Proxy::all(proxy) {
+            Ok(x) => {
+                info!("Using proxy: {}", proxy);
+                Ok(client.proxy(x))
+            },
+            Err(x) => {
+                gui.borrow_mut().do_error("Invalid proxy", &format!("The proxy URL {:?} is not valid. The error was:\n{}", proxy, x));
+                Err(())
+            },
+        }
+    }
+    let http_proxy = proxy_from_env(gui, &["HTTP_PROXY", "http_proxy"])?;
+    let https_proxy = proxy_from_env(gui, &["HTTPS_PROXY", "https_proxy"])?;
+    if http_proxy.is_none() && https_proxy.is_none() {
+        debug!("Not using a proxy");
+        return Ok(client)
+    }
+    let no_proxy: Vec<String> = first_env_var(&["NO_PROXY", "no_proxy"])
+        .map(|(_, x)| x.split(',').map(|x| x.trim().to_ascii_lowercase()).filter(|x| !x.is_empty()).collect())
+        .unwrap_or_default();
+    if let Some(x) = http_proxy.as_ref() {
+        info!("Using proxy for http: {}", x);
+    }
+    if let Some(x) = https_proxy.as_ref() {
+        info!("Using proxy for https: {}", x);
+    }
+    if !no_proxy.is_empty() {
+        info!("Not using a proxy for: {}", no_proxy.join(", "));
+    }
+    Ok(client.proxy(reqwest::Proxy::custom(move |url| {
+        if let Some(host) = url.host_str() {
+            let host = host.to_ascii_lowercase();
+            if no_proxy.iter().any(|x| no_proxy_matches(&host, x)) {
+                return None
+            }
+        }
+        match url.scheme() {
+            "http" => http_proxy.clone(),
+            "https" => https_proxy.clone(),
+            _ => None,
+        }
+    })))
+}
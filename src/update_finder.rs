@@ -1,15 +1,19 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::{HashMap, hash_map::Entry as HashMapEntry},
     env,
-    path::{Path, PathBuf},
+    fs::File,
+    io::Read,
+    path::{Component, Path, PathBuf},
     rc::Rc,
     sync::Arc,
 };
 use mlua::{
+    ChunkMode,
     Lua,
     FromLua,
     Function,
+    HookTriggers,
     MultiValue,
     Table,
     ThreadStatus,
@@ -20,6 +24,13 @@ use wax::Glob;
 
 use super::*;
 
+/// The largest file `read_text_file` will read.
+const MAX_TEXT_FILE_SIZE: u64 = 65536;
+/// The largest file `file_checksum` will hash.
+const MAX_CHECKSUM_FILE_SIZE: u64 = 256 << 20;
+/// The most paths `glob_files` will return.
+const MAX_GLOB_FILES: usize = 1000;
+
 fn sense(anchor: &Path, srcglob: &str) -> mlua::Result<bool> {
     let (glob, wants_dir) = if srcglob.ends_with("/") {
         (&srcglob[..srcglob.len()-1], true)
@@ -44,23 +55,187 @@ fn sense(anchor: &Path, srcglob: &str) -> mlua::Result<bool> {
     Ok(true)
 }
 
+/// Returns the version of the operating system, e.g. "10.0.22631" on Windows,
+/// "14.3.1" on macOS, or the kernel version on other Unixes.
+#[cfg(windows)]
+fn os_version() -> Option<String> {
+    use windows_sys::Win32::System::SystemInformation::OSVERSIONINFOW;
+    // `GetVersionExW` lies to programs without the right manifest;
+    // `RtlGetVersion` doesn't.
+    #[link(name = "ntdll")]
+    extern "system" {
+        fn RtlGetVersion(info: *mut OSVERSIONINFOW) -> i32;
+    }
+    let mut info: OSVERSIONINFOW = unsafe { std::mem::zeroed() };
+    info.dwOSVersionInfoSize = std::mem::size_of::<OSVERSIONINFOW>() as u32;
+    if unsafe { RtlGetVersion(&mut info) } != 0 { return None }
+    Some(format!("{}.{}.{}", info.dwMajorVersion, info.dwMinorVersion, info.dwBuildNumber))
+}
+
+#[cfg(target_os="macos")]
+fn os_version() -> Option<String> {
+    let output = std::process::Command::new("sw_vers").arg("-productVersion").output().ok()?;
+    if !output.status.success() { return None }
+    let version = String::from_utf8(output.stdout).ok()?;
+    Some(version.trim().to_string())
+}
+
+#[cfg(all(unix, not(target_os="macos")))]
+fn os_version() -> Option<String> {
+    let uname = nix::sys::utsname::uname().ok()?;
+    uname.release().to_str().map(str::to_string)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_version() -> Option<String> {
+    None
+}
+
+/// Reads a string (`REG_SZ` or `REG_EXPAND_SZ`) from the registry. `hive` is
+/// `"HKLM"`, `"HKCU"`, or `"HKCR"`. Returns `None` if the value isn't there,
+/// or isn't a string.
+#[cfg(windows)]
+fn windows_registry_read(hive: &str, key: &str, value: &str) -> mlua::Result<Option<String>> {
+    use winreg::{RegKey, enums::*, types::FromRegValue};
+    let hive = match hive {
+        "HKLM" => HKEY_LOCAL_MACHINE,
+        "HKCU" => HKEY_CURRENT_USER,
+        "HKCR" => HKEY_CLASSES_ROOT,
+        _ => return Err(mlua::Error::RuntimeError(format!("Unknown registry hive {:?} (must be \"HKLM\", \"HKCU\", or \"HKCR\")", hive))),
+    };
+    let raw = match RegKey::predef(hive).open_subkey(key).and_then(|x| x.get_raw_value(value)) {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+    match raw.vtype {
+        REG_SZ | REG_EXPAND_SZ => Ok(String::from_reg_value(&raw).ok()),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(not(windows))]
+fn windows_registry_read(_hive: &str, _key: &str, _value: &str) -> mlua::Result<Option<String>> {
+    Ok(None)
+}
+
+/// Asks Launch Services where the app with the given bundle identifier is.
+#[cfg(target_os="macos")]
+fn macos_bundle_path(bundle_id: &str) -> Option<String> {
+    use cacao::foundation::{id, NSString};
+    use objc::{class, msg_send, sel, sel_impl};
+    unsafe {
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let url: id = msg_send![workspace, URLForApplicationWithBundleIdentifier: NSString::new(bundle_id)];
+        if url.is_null() { return None }
+        let path: id = msg_send![url, path];
+        if path.is_null() { return None }
+        Some(NSString::retain(path).to_str().to_string())
+    }
+}
+
+#[cfg(not(target_os="macos"))]
+fn macos_bundle_path(_bundle_id: &str) -> Option<String> {
+    None
+}
+
+/// The user's data directory, followed by everything in `XDG_DATA_DIRS`.
+/// Empty except on Linux.
+fn xdg_data_dirs() -> Vec<String> {
+    if !cfg!(target_os="linux") { return vec![] }
+    let mut ret = vec![];
+    if let Some(x) = dirs::data_dir().and_then(|x| x.to_str().map(str::to_string)) {
+        ret.push(x);
+    }
+    // The default comes from the XDG Base Directory Specification.
+    let dirs = std::env::var("XDG_DATA_DIRS").ok().filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    ret.extend(dirs.split(':').filter(|x| !x.is_empty()).map(str::to_string));
+    ret
+}
+
+/// Where Steam itself might be installed.
+fn steam_roots() -> Vec<PathBuf> {
+    let mut ret = vec![];
+    if cfg!(windows) {
+        if let Ok(Some(x)) = windows_registry_read("HKCU", "Software\\Valve\\Steam", "SteamPath") {
+            ret.push(PathBuf::from(x));
+        }
+    }
+    else if let Some(home) = dirs::home_dir() {
+        if cfg!(target_os="macos") {
+            ret.push(home.join("Library/Application Support/Steam"));
+        }
+        else {
+            ret.push(home.join(".steam/steam"));
+            ret.push(home.join(".local/share/Steam"));
+        }
+    }
+    ret
+}
+
+/// Pulls every value with the given key out of a Valve KeyValues (`.vdf` or
+/// `.acf`) file. We don't care about the structure, only the values.
+fn vdf_values(text: &str, key: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '"' { continue }
+        let mut token = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => if let Some(c) = chars.next() { token.push(c) },
+                _ => token.push(c),
+            }
+        }
+        tokens.push(token);
+    }
+    tokens.windows(2).filter(|x| x[0].eq_ignore_ascii_case(key)).map(|x| x[1].clone()).collect()
+}
+
+/// Returns where Steam installed the game with the given app ID, if it did.
+fn detect_steam_library(app_id: u32) -> Option<String> {
+    let manifest_name = format!("appmanifest_{}.acf", app_id);
+    for root in steam_roots() {
+        let mut libraries = vec![root.clone()];
+        if let Ok(text) = std::fs::read_to_string(root.join("steamapps").join("libraryfolders.vdf")) {
+            libraries.extend(vdf_values(&text, "path").into_iter().map(PathBuf::from));
+        }
+        for library in libraries.iter() {
+            let steamapps = library.join("steamapps");
+            let text = match std::fs::read_to_string(steamapps.join(&manifest_name)) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            // An installdir that tries to go anywhere funny is ignored.
+            if let Some(installdir) = vdf_values(&text, "installdir").into_iter().find(|x| !is_fishy_path(x)) {
+                debug!("Steam app {} is in library {:?}", app_id, library);
+                return steamapps.join("common").join(installdir).to_str().map(str::to_string)
+            }
+        }
+    }
+    None
+}
+
 struct UpdateFinder {
-    gui: Rc<RefCell<dyn Gui>>,
-    verbose: bool,
     dirs: HashMap<String, PathBuf>,
     basedir: Option<PathBuf>,
+    /// Where `popd` goes back to.
+    dir_stack: Vec<PathBuf>,
+    /// From `--basedir`. If set, `basedir` won't go anywhere else.
+    basedir_override: Option<PathBuf>,
     url: Url,
     installs: Vec<(PathBuf, Url)>,
     deletes: HashMap<PathBuf, Vec<String>>,
 }
 
 impl UpdateFinder {
-    fn new(gui: Rc<RefCell<dyn Gui>>, verbose: bool, url: Url) -> UpdateFinder {
+    fn new(url: Url, basedir_override: Option<PathBuf>) -> UpdateFinder {
         UpdateFinder {
-            gui,
-            verbose,
             dirs: HashMap::new(),
             basedir: None,
+            dir_stack: vec![],
+            basedir_override,
             url,
             installs: vec![],
             deletes: HashMap::new(),
@@ -74,8 +249,17 @@ trait UpdateFinderRef {
     fn check_detected_dir(&self, var: &str, candidate: &Path, silhouette: &Table) -> mlua::Result<bool>;
     fn detect_dir(&self, lua: &Lua, id: String, name: String, candidate_iter: Function, silhouette: Table) -> mlua::Result<()>;
     fn basedir(&self, lua: &Lua, target: String) -> mlua::Result<()>;
+    fn get_detected_dirs(&self, _lua: &Lua) -> mlua::Result<HashMap<String, String>>;
     fn cd(&self, lua: &Lua, target: String) -> mlua::Result<()>;
+    fn pushd(&self, lua: &Lua, target: String) -> mlua::Result<()>;
+    fn popd(&self, _lua: &Lua) -> mlua::Result<()>;
     fn sense(&self, _lua: &Lua, target: String) -> mlua::Result<bool>;
+    fn sense_not(&self, _lua: &Lua, target: String) -> mlua::Result<bool>;
+    fn file_exists(&self, _lua: &Lua, target: String) -> mlua::Result<bool>;
+    fn read_text_file(&self, _lua: &Lua, target: String) -> mlua::Result<Option<String>>;
+    fn glob_files(&self, _lua: &Lua, target: String) -> mlua::Result<Vec<String>>;
+    fn file_checksum(&self, _lua: &Lua, target: String) -> mlua::Result<Option<String>>;
+    fn file_size(&self, _lua: &Lua, target: String) -> mlua::Result<Option<f64>>;
     fn install(&self, _lua: &Lua, target: String) -> mlua::Result<()>;
     fn delete_unmatched(&self, _lua: &Lua, target: String) -> mlua::Result<()>;
 }
@@ -88,41 +272,36 @@ impl UpdateFinderRef for Rc<RefCell<UpdateFinder>> {
         Ok(self.try_borrow().expect("Attempt to perform unsafe borrow on UpdateFinder"))
     }
     fn check_detected_dir(&self, var: &str, candidate: &Path, silhouette: &Table) -> mlua::Result<bool> {
-        let verbose = self.refconst()?.verbose;
         if !candidate.is_absolute() {
             return Err(mlua::Error::RuntimeError(format!("Path is invalid (must be absolute)"))); 
         }
+        // `basedir` compares against `--basedir` without resolving anything,
+        // so `/base/../etc` would look like it's inside `/base`.
+        if candidate.components().any(|x| x == Component::ParentDir) {
+            return Err(mlua::Error::RuntimeError("Path is invalid (must not contain \"..\")".to_string()));
+        }
         let mut ok = true;
         if let Ok(globs) = silhouette.get::<_, Vec<String>>("sense") {
             for srcglob in globs.iter() {
                 if !sense(candidate, srcglob)? {
-                    if verbose {
-                        self.refconst()?.gui.borrow_mut().verbose(&format!("    Rejected: doesn't match glob {:?}", srcglob));
-                    }            
+                    debug!("    Rejected: doesn't match glob {:?}", srcglob);
                     ok = false;
                 }
             }
         }
         if ok {
-            if verbose {
-                self.refconst()?.gui.borrow_mut().verbose(&format!("    Accepted!"));
-            }
+            debug!("    Accepted!");
             self.refmut()?.dirs.insert(var.to_string(), candidate.to_path_buf());
         }
         Ok(ok)
     }
     fn detect_dir(&self, lua: &Lua, id: String, name: String, candidate_iter: Function, silhouette: Table) -> mlua::Result<()> {
-        let verbose = self.refconst()?.verbose;
         if self.refconst()?.dirs.contains_key(&id) {
             return Ok(())
         }
-        if verbose {
-            self.refconst()?.gui.borrow_mut().verbose(&format!("Detecting {:?} ({}):", id, name));
-        }
+        debug!("Detecting {:?} ({}):", id, name);
         if let Some(wo) = env::var_os(&id) {
-            if verbose {
-                self.refconst()?.gui.borrow_mut().verbose(&format!("  Environment variable: {:?}", wo));
-            }
+            debug!("  Environment variable: {:?}", wo);
             if self.check_detected_dir(&id, &Path::new(&wo), &silhouette)? { return Ok(()) }
         }
         let cor = lua.create_thread(candidate_iter)?;
@@ -130,9 +309,7 @@ impl UpdateFinderRef for Rc<RefCell<UpdateFinder>> {
             let candidate: Option<String> = cor.resume(())?;
             match candidate {
                 Some(wo) => {
-                    if verbose {
-                        self.refconst()?.gui.borrow_mut().verbose(&format!("  Index suggests: {:?}", wo));
-                    }
+                    debug!("  Index suggests: {:?}", wo);
                     if self.check_detected_dir(&id, &Path::new(&wo), &silhouette)? { return Ok(()) }
                 },
                 None => break,
@@ -141,18 +318,27 @@ impl UpdateFinderRef for Rc<RefCell<UpdateFinder>> {
         Ok(())
     }
     fn basedir(&self, _lua: &Lua, target: String) -> mlua::Result<()> {
+        let basedir_override = self.refconst()?.basedir_override.clone();
         let dir = match self.refconst()?.dirs.get(&target) {
+            // `basedir(basedir_override)` is allowed without detecting it first.
+            None if basedir_override.as_ref().map(|x| x.as_os_str() == target.as_str()).unwrap_or(false) => basedir_override.clone().unwrap(),
             None => {
                 return Err(mlua::Error::RuntimeError(format!("No detected base directory identified as {:?} found. Use `detect_dir` before calling basedir.", target)));
             },
             Some(x) => x.clone(),
         };
-        if self.refconst()?.verbose {
-            self.refconst()?.gui.borrow_mut().verbose(&format!("Entering {:?} ({})", dir, target));
+        if let Some(basedir_override) = basedir_override.as_ref() {
+            if !dir.starts_with(basedir_override) {
+                return Err(mlua::Error::RuntimeError(format!("The base directory must be inside {:?}, because --basedir was given.", basedir_override)));
+            }
         }
+        debug!("Entering {:?} ({})", dir, target);
         self.refmut()?.basedir = Some(dir);
         Ok(())
     }
+    fn get_detected_dirs(&self, _lua: &Lua) -> mlua::Result<HashMap<String, String>> {
+        Ok(self.refconst()?.dirs.iter().map(|(id, path)| (id.clone(), path.to_string_lossy().into_owned())).collect())
+    }
     fn cd(&self, _lua: &Lua, target: String) -> mlua::Result<()> {
         if is_fishy_path(&target) {
             return Err(mlua::Error::RuntimeError(format!("You cannot cd to an absolute path, or use any path component that starts with a .")));
@@ -164,20 +350,166 @@ impl UpdateFinderRef for Rc<RefCell<UpdateFinder>> {
         else {
             return Err(mlua::Error::RuntimeError(format!("You must use basedir before you can cd")));
         }
-        if me.verbose {
-            me.gui.borrow_mut().verbose(&format!("Entering {:?}", me.basedir.as_ref().unwrap()));
-        }
+        debug!("Entering {:?}", me.basedir.as_ref().unwrap());
         Ok(())
     }
-    fn sense(&self, _lua: &Lua, target: String) -> mlua::Result<bool> {
+    fn pushd(&self, lua: &Lua, target: String) -> mlua::Result<()> {
+        let previous = self.refconst()?.basedir.clone();
+        self.cd(lua, target)?;
+        // cd would have failed if there were no basedir
+        self.refmut()?.dir_stack.push(previous.unwrap());
+        Ok(())
+    }
+    fn popd(&self, _lua: &Lua) -> mlua::Result<()> {
         let mut me = self.refmut()?;
-        if let Some(basedir) = me.basedir.as_mut() {
+        match me.dir_stack.pop() {
+            Some(x) => {
+                debug!("Returning to {:?}", x);
+                me.basedir = Some(x);
+                Ok(())
+            },
+            None => Err(mlua::Error::RuntimeError("popd without a matching pushd".to_string())),
+        }
+    }
+    fn sense(&self, _lua: &Lua, target: String) -> mlua::Result<bool> {
+        if Path::new(&target).is_absolute() {
+            // No globbing here, only a straight check.
+            let wants_dir = target.ends_with("/");
+            let path = Path::new(&target);
+            if path.components().any(|x| x == Component::ParentDir) {
+                return Err(mlua::Error::RuntimeError("Semantic components (such as \"..\") are not allowed".to_string()));
+            }
+            return Ok(std::fs::metadata(path).map(|x| x.is_dir() == wants_dir).unwrap_or(false))
+        }
+        let me = self.refconst()?;
+        if let Some(basedir) = me.basedir.as_ref() {
             sense(basedir, &target)
         }
         else {
-            Err(mlua::Error::RuntimeError(format!("You must use basedir before you can cd")))
+            // Before there's a basedir, relative paths are relative to where
+            // we are.
+            let exe = env::current_exe().map_err(|x| mlua::Error::RuntimeError(format!("Couldn't find the updater's own location: {}", x)))?;
+            match exe.parent() {
+                Some(exe_dir) => sense(exe_dir, &target),
+                None => Ok(false),
+            }
         }
     }
+    fn sense_not(&self, lua: &Lua, target: String) -> mlua::Result<bool> {
+        self.sense(lua, target).map(|x| !x)
+    }
+    fn file_exists(&self, _lua: &Lua, target: String) -> mlua::Result<bool> {
+        let me = self.refconst()?;
+        if let Some(basedir) = me.basedir.as_ref() {
+            // Not worth an error; a path like that can't exist as far as the
+            // index is concerned.
+            if is_fishy_path(&target) { return Ok(false) }
+            Ok(basedir.join(&target).exists())
+        }
+        else {
+            Err(mlua::Error::RuntimeError("You must call basedir before file_exists".to_string()))
+        }
+    }
+    fn read_text_file(&self, _lua: &Lua, target: String) -> mlua::Result<Option<String>> {
+        let me = self.refconst()?;
+        let path = if let Some(basedir) = me.basedir.as_ref() {
+            if is_fishy_path(&target) {
+                return Err(mlua::Error::RuntimeError("You cannot read an absolute path, or use any path component that starts with a .".to_string()));
+            }
+            basedir.join(&target)
+        }
+        else {
+            return Err(mlua::Error::RuntimeError("You must call basedir before read_text_file".to_string()))
+        };
+        let mut text = String::new();
+        match File::open(&path).and_then(|x| x.take(MAX_TEXT_FILE_SIZE + 1).read_to_string(&mut text)) {
+            Ok(_) if text.len() as u64 <= MAX_TEXT_FILE_SIZE => Ok(Some(text)),
+            Ok(_) => {
+                debug!("{:?}: too big for read_text_file", path);
+                Ok(None)
+            },
+            Err(x) => {
+                debug!("{:?}: read_text_file failed: {}", path, x);
+                Ok(None)
+            },
+        }
+    }
+    fn file_checksum(&self, _lua: &Lua, target: String) -> mlua::Result<Option<String>> {
+        let me = self.refconst()?;
+        let path = if let Some(basedir) = me.basedir.as_ref() {
+            if is_fishy_path(&target) {
+                return Err(mlua::Error::RuntimeError("You cannot read an absolute path, or use any path component that starts with a .".to_string()));
+            }
+            basedir.join(&target)
+        }
+        else {
+            return Err(mlua::Error::RuntimeError("You must call basedir before file_checksum".to_string()))
+        };
+        let result = File::open(&path).and_then(|mut f| {
+            if f.metadata()?.len() > MAX_CHECKSUM_FILE_SIZE {
+                return Ok(None)
+            }
+            let mut hasher = lsx::sha256::BufSha256::new();
+            let mut buf = [0u8; 32768];
+            loop {
+                match f.read(&mut buf[..])? {
+                    0 => break,
+                    red => hasher.update(&buf[..red]),
+                }
+            }
+            Ok(Some(hex::encode(hasher.finish(&[]))))
+        });
+        match result {
+            Ok(None) => {
+                debug!("{:?}: too big for file_checksum", path);
+                Ok(None)
+            },
+            Ok(x) => Ok(x),
+            Err(x) => {
+                debug!("{:?}: file_checksum failed: {}", path, x);
+                Ok(None)
+            },
+        }
+    }
+    fn file_size(&self, _lua: &Lua, target: String) -> mlua::Result<Option<f64>> {
+        let me = self.refconst()?;
+        if let Some(basedir) = me.basedir.as_ref() {
+            if is_fishy_path(&target) { return Ok(None) }
+            // Precision goes out the window past 2^53 bytes. We can live with
+            // that.
+            Ok(std::fs::metadata(basedir.join(&target)).ok().map(|x| x.len() as f64))
+        }
+        else {
+            Err(mlua::Error::RuntimeError("You must call basedir before file_size".to_string()))
+        }
+    }
+    fn glob_files(&self, _lua: &Lua, target: String) -> mlua::Result<Vec<String>> {
+        let me = self.refconst()?;
+        let basedir = if let Some(basedir) = me.basedir.as_ref() { basedir }
+        else {
+            return Err(mlua::Error::RuntimeError("You must call basedir before glob_files".to_string()))
+        };
+        let glob = match Glob::new(&target) {
+            Ok(x) => x,
+            Err(x) => {
+                return Err(mlua::Error::RuntimeError(format!("Invalid glob {:?}: {}", target, x)));
+            },
+        };
+        if glob.has_root() || glob.has_semantic_literals() || is_fishy_path(&target) {
+            return Err(mlua::Error::RuntimeError(format!("Rooted globs, and semantic components (such as \"..\"), are not allowed")));
+        }
+        let mut ret = vec![];
+        for entry in glob.walk(basedir).filter_map(Result::ok) {
+            if ret.len() >= MAX_GLOB_FILES {
+                debug!("glob_files {:?}: stopping after {} matches", target, MAX_GLOB_FILES);
+                break
+            }
+            if let Some(path) = entry.path().strip_prefix(basedir).ok().and_then(Path::to_str) {
+                ret.push(path.to_string());
+            }
+        }
+        Ok(ret)
+    }
     fn install(&self, _lua: &Lua, target: String) -> mlua::Result<()> {
         let mut me = self.refmut()?;
         let url = me.url.join(&target).map_err(|_| {
@@ -187,6 +519,10 @@ impl UpdateFinderRef for Rc<RefCell<UpdateFinder>> {
         else {
             return Err(mlua::Error::RuntimeError(format!("You must call basedir before install")))
         };
+        if me.installs.iter().any(|(x, y)| *x == basedir && *y == url) {
+            debug!("Already installing {} into {:?}, skipping", url, basedir);
+            return Ok(())
+        }
         me.installs.push((basedir, url));
         Ok(())
     }
@@ -216,16 +552,20 @@ impl UpdateFinderRef for Rc<RefCell<UpdateFinder>> {
     }
 }
 
-pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, verbose: bool, body: &[u8], url: Url) -> Result<(Vec<(PathBuf, Url)>, HashMap<PathBuf, Vec<String>>), ()> {
+/// How often, in Lua VM instructions, we check whether the index has run for
+/// too long.
+const LUA_LIMIT_GRANULARITY: u32 = 10000;
+
+/// `lua_limit` is the most VM instructions the index may execute, or 0 for no
+/// limit. `basedir_override` comes from `--basedir`, and `channel` from
+/// `--channel`.
+pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, body: &[u8], url: Url, lua_limit: u64, basedir_override: Option<&Path>, channel: &str) -> Result<(Vec<(PathBuf, Url)>, HashMap<PathBuf, Vec<String>>), FindUpdatesError> {
     const UNSAFE_FUNCTIONS: &[&str] = &[
         "dofile", "loadfile",
     ];
     let lua = match mlua::Lua::new_with(mlua::StdLib::COROUTINE | mlua::StdLib::MATH | mlua::StdLib::STRING | mlua::StdLib::TABLE, mlua::LuaOptions::new().catch_rust_panics(false)) {
         Ok(x) => x,
-        Err(x) => {
-            gui.borrow_mut().do_error("Internal error", &format!("Unable to initialize Lua. The error was:\n{}", x));
-            return Err(());
-        },
+        Err(x) => return Err(FindUpdatesError::LuaInitFailed(x)),
     };
     for func in UNSAFE_FUNCTIONS.iter() {
         lua.globals().set(*func, Nil).unwrap();
@@ -235,15 +575,39 @@ pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, verbose: bool, body: &[u8], url:
     if cfg!(target_os="macos") { lua.globals().set("macos", true).unwrap(); }
     lua.globals().set("target_os", cfg!(target_os)).unwrap();
     lua.globals().set("target_family", cfg!(target_family)).unwrap();
+    lua.globals().set("target_arch", std::env::consts::ARCH).unwrap();
+    lua.globals().set("is_64bit", cfg!(target_pointer_width = "64")).unwrap();
     lua.globals().set("tupdate_version", env!("CARGO_PKG_VERSION")).unwrap();
-    let uf = Rc::new(RefCell::new(UpdateFinder::new(gui.clone(), verbose, url)));
-    if verbose {
-        let gui = gui.clone();
-        lua.globals().set("print", lua.create_function_mut(move |lua, things: MultiValue| { gui.borrow_mut().verbose(&things.into_iter().map(|x| String::from_lua(x, lua)).collect::<Result<Vec<String>, _>>()?.join("\t")); Ok(()) }).unwrap()).unwrap();
-    }
-    else {
-        lua.globals().set("print", lua.create_function_mut(move |_lua, _things: MultiValue| -> Result<_, _> { Ok(()) }).unwrap()).unwrap();
+    lua.globals().set("channel", channel).unwrap();
+    if let Some(basedir_override) = basedir_override {
+        lua.globals().set("basedir_override", basedir_override.to_string_lossy().into_owned()).unwrap();
     }
+    let uf = Rc::new(RefCell::new(UpdateFinder::new(url, basedir_override.map(Path::to_path_buf))));
+    lua.globals().set("print", lua.create_function_mut(move |lua, things: MultiValue| { info!("{}", things.into_iter().map(|x| String::from_lua(x, lua)).collect::<Result<Vec<String>, _>>()?.join("\t")); Ok(()) }).unwrap()).unwrap();
+    lua.globals().set("os_version", lua.create_function_mut(move |_lua, _: ()| {
+        Ok(os_version())
+    }).unwrap()).unwrap();
+    lua.globals().set("windows_registry_read", lua.create_function_mut(move |_lua, param: (String, String, String)| {
+        windows_registry_read(&param.0, &param.1, &param.2)
+    }).unwrap()).unwrap();
+    lua.globals().set("detect_steam_library", lua.create_function_mut(move |_lua, app_id: u32| {
+        Ok(detect_steam_library(app_id))
+    }).unwrap()).unwrap();
+    lua.globals().set("macos_bundle_path", lua.create_function_mut(move |_lua, bundle_id: String| {
+        Ok(macos_bundle_path(&bundle_id))
+    }).unwrap()).unwrap();
+    lua.globals().set("xdg_data_dirs", lua.create_function_mut(move |_lua, _: ()| {
+        Ok(xdg_data_dirs())
+    }).unwrap()).unwrap();
+    lua.globals().set("home_dir", lua.create_function_mut(move |_lua, _: ()| {
+        Ok(dirs::home_dir().and_then(|x| x.to_str().map(str::to_string)))
+    }).unwrap()).unwrap();
+    lua.globals().set("app_data_dir", lua.create_function_mut(move |_lua, appname: String| {
+        if is_fishy_path(&appname) {
+            return Err(mlua::Error::RuntimeError("Application name must not be an absolute path, or have any path component that starts with a .".to_string()));
+        }
+        Ok(dirs::data_dir().and_then(|x| x.join(&appname).to_str().map(str::to_string)))
+    }).unwrap()).unwrap();
     lua.globals().set("getenv", lua.create_function_mut(move |_lua, env: String| {
         Ok(std::env::var(&env).ok())
     }).unwrap()).unwrap();
@@ -259,18 +623,72 @@ pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, verbose: bool, body: &[u8], url:
             uf.basedir(lua, param)
         }).unwrap()).unwrap();
     }
+    {
+        let uf = uf.clone();
+        lua.globals().set("get_detected_dirs", lua.create_function_mut(move |lua, _: ()| {
+            uf.get_detected_dirs(lua)
+        }).unwrap()).unwrap();
+    }
     {
         let uf = uf.clone();
         lua.globals().set("cd", lua.create_function_mut(move |lua, param: String| {
             uf.cd(lua, param)
         }).unwrap()).unwrap();
     }
+    {
+        let uf = uf.clone();
+        lua.globals().set("pushd", lua.create_function_mut(move |lua, param: String| {
+            uf.pushd(lua, param)
+        }).unwrap()).unwrap();
+    }
+    {
+        let uf = uf.clone();
+        lua.globals().set("popd", lua.create_function_mut(move |lua, _: ()| {
+            uf.popd(lua)
+        }).unwrap()).unwrap();
+    }
     {
         let uf = uf.clone();
         lua.globals().set("sense", lua.create_function_mut(move |lua, param: String| {
             uf.sense(lua, param)
         }).unwrap()).unwrap();
     }
+    {
+        let uf = uf.clone();
+        lua.globals().set("file_exists", lua.create_function_mut(move |lua, param: String| {
+            uf.file_exists(lua, param)
+        }).unwrap()).unwrap();
+    }
+    {
+        let uf = uf.clone();
+        lua.globals().set("file_checksum", lua.create_function_mut(move |lua, param: String| {
+            uf.file_checksum(lua, param)
+        }).unwrap()).unwrap();
+    }
+    {
+        let uf = uf.clone();
+        lua.globals().set("file_size", lua.create_function_mut(move |lua, param: String| {
+            uf.file_size(lua, param)
+        }).unwrap()).unwrap();
+    }
+    {
+        let uf = uf.clone();
+        lua.globals().set("read_text_file", lua.create_function_mut(move |lua, param: String| {
+            uf.read_text_file(lua, param)
+        }).unwrap()).unwrap();
+    }
+    {
+        let uf = uf.clone();
+        lua.globals().set("glob_files", lua.create_function_mut(move |lua, param: String| {
+            uf.glob_files(lua, param)
+        }).unwrap()).unwrap();
+    }
+    {
+        let uf = uf.clone();
+        lua.globals().set("sense_not", lua.create_function_mut(move |lua, param: String| {
+            uf.sense_not(lua, param)
+        }).unwrap()).unwrap();
+    }
     {
         let uf = uf.clone();
         lua.globals().set("install", lua.create_function_mut(move |lua, param: String| {
@@ -283,6 +701,23 @@ pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, verbose: bool, body: &[u8], url:
             uf.delete_unmatched(lua, param)
         }).unwrap()).unwrap();
     }
+    {
+        let gui = gui.clone();
+        lua.globals().set("do_progress", lua.create_function_mut(move |_lua, param: (String, String, Option<f64>)| {
+            if param.2.map(f64::is_nan).unwrap_or(false) {
+                return Err(mlua::Error::RuntimeError("Progress fraction must be a number between 0 and 1, or nil".to_string()));
+            }
+            gui.borrow_mut().set_progress(&format!("(script) {}", param.0), &param.1, param.2.map(|x| x.clamp(0.0, 1.0) as f32));
+            Ok(())
+        }).unwrap()).unwrap();
+    }
+    {
+        let gui = gui.clone();
+        lua.globals().set("set_window_title", lua.create_function_mut(move |_lua, title: String| {
+            gui.borrow_mut().set_title(&title);
+            Ok(())
+        }).unwrap()).unwrap();
+    }
     {
         let gui = gui.clone();
         lua.globals().set("do_message", lua.create_function_mut(move |_lua, param: (String, String)| {
@@ -296,6 +731,12 @@ pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, verbose: bool, body: &[u8], url:
             Ok(gui.borrow_mut().do_warning(&param.0, &param.1, param.2.unwrap_or(false)))
         }).unwrap()).unwrap();
     }
+    {
+        let gui = gui.clone();
+        lua.globals().set("do_question", lua.create_function_mut(move |_lua, param: (String, String)| {
+            Ok(gui.borrow_mut().do_question(&param.0, &param.1))
+        }).unwrap()).unwrap();
+    }
     {
         let gui = gui.clone();
         lua.globals().set("do_error", lua.create_function_mut(move |_lua, param: (String, String)| {
@@ -303,37 +744,69 @@ pub fn find_updates(gui: Rc<RefCell<dyn Gui>>, verbose: bool, body: &[u8], url:
             Ok(())
         }).unwrap()).unwrap();
     }
+    {
+        let gui = gui.clone();
+        lua.globals().set("abort_with_message", lua.create_function_mut(move |_lua, param: (String, String)| -> mlua::Result<()> {
+            gui.borrow_mut().do_error(&param.0, &param.1);
+            Err(mlua::Error::ExternalError(Arc::new(BailOut)))
+        }).unwrap()).unwrap();
+    }
     {
         lua.globals().set("bail_out", lua.create_function_mut(move |_lua, _: ()| -> mlua::Result<()> {
             Err(mlua::Error::ExternalError(Arc::new(BailOut)))
         }).unwrap()).unwrap();
     }
-    match lua.load(body).set_name("@index").unwrap().exec() {
-        Ok(_) => (),
-        Err(x) => {
-            if let mlua::Error::CallbackError { cause, .. } = x {
-                let f = format!("{}", cause);
-                if f != "BAIL OUT" {
-                    gui.borrow_mut().do_error("Lua error", &format!("An error occurred while processing the update index. The error was:\n{}", cause));
-                }
+    if lua_limit != 0 {
+        let executed = Cell::new(0u64);
+        lua.set_hook(HookTriggers { every_nth_instruction: Some(LUA_LIMIT_GRANULARITY), ..Default::default() }, move |_lua, _debug| {
+            executed.set(executed.get() + LUA_LIMIT_GRANULARITY as u64);
+            if executed.get() >= lua_limit {
+                Err(mlua::Error::ExternalError(Arc::new(InstructionLimit)))
             }
-            else {
-                gui.borrow_mut().do_error("Lua error", &format!("An error occurred while processing the update index. The error was:\n{}", x));
-            }
-            return Err(());
+            else { Ok(()) }
+        }).unwrap();
+    }
+    // Bytecode can do things that the sandbox can't stop, so only accept
+    // source.
+    match lua.load(body).set_name("@index").unwrap().set_mode(ChunkMode::Text).exec() {
+        Ok(_) => (),
+        Err(mlua::Error::SyntaxError { message, .. }) if message.contains("attempt to load a binary chunk") => {
+            return Err(FindUpdatesError::SecurityViolation("The update index contains compiled Lua bytecode, which is not allowed.".to_string()));
+        },
+        Err(x) if is_instruction_limit(&x) => {
+            debug!("Update index hit the instruction limit ({})", lua_limit);
+            return Err(FindUpdatesError::InstructionLimit);
         },
+        Err(x) if is_bail_out(&x) => return Err(FindUpdatesError::BailOut),
+        Err(mlua::Error::CallbackError { cause, .. }) => return Err(FindUpdatesError::ScriptError((*cause).clone())),
+        Err(x) => return Err(FindUpdatesError::ScriptError(x)),
     }
     drop(lua);
     let uf = match Rc::try_unwrap(uf) {
         Ok(x) => x.into_inner(),
         Err(_) => panic!("Dangling reference to UpdateFinder"),
     };
-    if verbose {
-        gui.borrow_mut().verbose("Finished examining update index.");
-    }
+    debug!("Finished examining update index.");
     Ok((uf.installs, uf.deletes))
 }
 
+/// Why `find_updates` didn't find any updates.
+#[derive(Debug)]
+pub enum FindUpdatesError {
+    /// We couldn't even set up a Lua state.
+    LuaInitFailed(mlua::Error),
+    /// The index script raised an error.
+    ScriptError(mlua::Error),
+    /// The index script gave up on purpose, with `bail_out` or
+    /// `abort_with_message`. Anything the user needs to know has already been
+    /// shown to them.
+    BailOut,
+    /// The index script ran for longer than `lua_limit` allows.
+    InstructionLimit,
+    /// The index tried to do something it must not be allowed to do.
+    SecurityViolation(String),
+}
+
 #[derive(Debug)]
 struct BailOut;
 impl std::error::Error for BailOut {}
@@ -344,3 +817,121 @@ impl std::fmt::Display for BailOut {
     }
 }
 
+#[derive(Debug)]
+struct InstructionLimit;
+impl std::error::Error for InstructionLimit {}
+
+impl std::fmt::Display for InstructionLimit {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "instruction limit reached")
+    }
+}
+
+/// The hook's error may come back to us wrapped, if it went off while Lua
+/// was calling one of our functions.
+fn is_instruction_limit(x: &mlua::Error) -> bool {
+    match x {
+        mlua::Error::ExternalError(x) => x.is::<InstructionLimit>(),
+        mlua::Error::CallbackError { cause, .. } => is_instruction_limit(cause),
+        _ => false,
+    }
+}
+
+/// Like `is_instruction_limit`, but for `BailOut`.
+fn is_bail_out(x: &mlua::Error) -> bool {
+    match x {
+        mlua::Error::ExternalError(x) => x.is::<BailOut>(),
+        mlua::Error::CallbackError { cause, .. } => is_bail_out(cause),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gui::mock::MockGui;
+
+    #[test]
+    fn ordinary_paths_are_fine() {
+        for path in ["file.dat", "games/myapp/file.dat", "games\\file.dat", "file..dat", "games/C:/file.dat"] {
+            assert!(!is_fishy_path(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_drive_letter_forward_slash() {
+        assert!(is_fishy_path("C:/games/myapp/file.dat"));
+    }
+
+    #[test]
+    fn rejects_drive_letter_backslash() {
+        assert!(is_fishy_path("C:\\games\\file.dat"));
+    }
+
+    #[test]
+    fn rejects_drive_letter_lowercase_relative() {
+        assert!(is_fishy_path("c:file.dat"));
+    }
+
+    #[test]
+    fn rejects_parent_dir_component() {
+        assert!(is_fishy_path("games/../file.dat"));
+        assert!(is_fishy_path("games\\..\\file.dat"));
+        assert!(is_fishy_path("games/.."));
+        assert!(is_fishy_path(".."));
+    }
+
+    #[test]
+    fn rejects_leading_dot() {
+        assert!(is_fishy_path(".hidden"));
+    }
+
+    #[test]
+    fn rejects_leading_separator() {
+        assert!(is_fishy_path("/etc/passwd"));
+        assert!(is_fishy_path("\\Windows\\win.ini"));
+    }
+
+    #[test]
+    fn rejects_dot_component() {
+        assert!(is_fishy_path("games/.hidden"));
+        assert!(is_fishy_path("games\\.hidden"));
+    }
+
+    #[test]
+    fn rejects_nul() {
+        assert!(is_fishy_path("file\0.dat"));
+    }
+
+    #[test]
+    fn rejects_overlong() {
+        assert!(is_fishy_path(&"a".repeat(MAX_PATH_COMPONENT_LENGTH + 1)));
+        assert!(is_fishy_path(&["a"; MAX_PATH_LENGTH / 2 + 1].join("/")));
+    }
+
+    #[test]
+    fn detected_dir_cannot_escape_basedir_override() {
+        let gui: Rc<RefCell<dyn Gui>> = Rc::new(RefCell::new(MockGui::new(false)));
+        let index = "detect_dir(\"GAME\", \"Game\", function() coroutine.yield(\"/base/../etc\") end, {})\nbasedir(\"GAME\")\ninstall(\"main.cat\")\n";
+        let result = find_updates(gui, index.as_bytes(), Url::parse("https://example.com/").unwrap(), 0, Some(Path::new("/base")), "stable");
+        assert!(matches!(result, Err(FindUpdatesError::ScriptError(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn rejects_bytecode_index() {
+        let bytecode = Lua::new().load("basedir(basedir_override)").into_function().unwrap().dump(false);
+        let gui: Rc<RefCell<dyn Gui>> = Rc::new(RefCell::new(MockGui::new(false)));
+        let result = find_updates(gui, &bytecode, Url::parse("https://example.com/").unwrap(), 0, Some(Path::new("/base")), "stable");
+        assert!(matches!(result, Err(FindUpdatesError::SecurityViolation(_))));
+    }
+
+    #[test]
+    fn index_cannot_cd_to_drive_letter_or_parent() {
+        for target in ["C:/games", "C:\\games", "games/../.."] {
+            let gui: Rc<RefCell<dyn Gui>> = Rc::new(RefCell::new(MockGui::new(false)));
+            let index = format!("basedir(basedir_override)\ncd({:?})\n", target);
+            let result = find_updates(gui, index.as_bytes(), Url::parse("https://example.com/").unwrap(), 0, Some(Path::new("/base")), "stable");
+            assert!(matches!(result, Err(FindUpdatesError::ScriptError(_))), "{:?}", target);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Runs the real `tupdate` binary, with the batch GUI, against a tiny HTTP
+//! server on localhost.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+};
+
+/// An HTTP server that answers `GET` requests out of a map from path to body.
+/// Runs until the test process exits.
+struct MockServer {
+    port: u16,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MockServer {
+    fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let server_files = files.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let files = server_files.clone();
+                std::thread::spawn(move || serve(stream, &files));
+            }
+        });
+        MockServer { port, files }
+    }
+    fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}/{}", self.port, path)
+    }
+    fn put(&self, path: &str, body: impl Into<Vec<u8>>) {
+        self.files.lock().unwrap().insert(format!("/{}", path), body.into());
+    }
+}
+
+fn serve(stream: TcpStream, files: &Mutex<HashMap<String, Vec<u8>>>) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() { return }
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) if line == "\r\n" => break,
+            Ok(_) => (),
+        }
+    }
+    let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+    let body = files.lock().unwrap().get(&path).cloned();
+    let mut stream = reader.into_inner();
+    let _ = match body {
+        Some(body) => {
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            stream.write_all(&body)
+        },
+        None => write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+}
+
+/// A directory that is deleted when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("tupdate-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Builds a version 1 catalog listing the given files.
+fn make_catalog(files: &[(&str, &[u8])]) -> Vec<u8> {
+    make_catalog_with_header(files, b"\xFFTCat", &[])
+}
+
+/// Builds a catalog listing the given files, with the given magic number
+/// before the checksum, and `extra_header` after the size.
+fn make_catalog_with_header(files: &[(&str, &[u8])], magic: &[u8], extra_header: &[u8]) -> Vec<u8> {
+    let mut entries = vec![];
+    for (path, contents) in files.iter() {
+        entries.extend_from_slice(path.as_bytes());
+        entries.push(b'\n');
+        entries.extend_from_slice(&lsx::sha256::hash(contents));
+        entries.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+        entries.extend_from_slice(&0u16.to_be_bytes());
+    }
+    let mut ret = magic.to_vec();
+    ret.extend_from_slice(&lsx::sha256::hash(&entries));
+    ret.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    ret.extend_from_slice(extra_header);
+    let mut encoder = flate2::write::ZlibEncoder::new(ret, flate2::Compression::default());
+    encoder.write_all(&entries).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Serves an index that installs `main.cat` into `--basedir`, deleting
+/// anything it doesn't list, and a catalog and files to go with it.
+fn publish(server: &MockServer, files: &[(&str, &[u8])]) {
+    server.put("index.lua", "basedir(basedir_override)\ninstall(\"main.cat\")\ndelete_unmatched(\"*\")\n");
+    server.put("main.cat", make_catalog(files));
+    for (path, contents) in files.iter() {
+        server.put(path, *contents);
+    }
+}
+
+/// Runs tupdate against the server, installing into `basedir`. Returns its
+/// exit status.
+fn run_tupdate(server: &MockServer, home: &TempDir, basedir: &Path) -> i32 {
+    run_tupdate_with(server, home, basedir, &[])
+}
+
+/// As `run_tupdate`, but with some extra arguments.
+fn run_tupdate_with(server: &MockServer, home: &TempDir, basedir: &Path, extra_args: &[&str]) -> i32 {
+    let output = Command::new(env!("CARGO_BIN_EXE_tupdate"))
+        .args(["--gui", "batch", "--yes", "--no-cache", "--retries", "0", "--basedir"])
+        .arg(basedir)
+        .args(extra_args)
+        .arg(server.url("index.lua"))
+        .current_dir(home.path())
+        .env_remove("TUPDATE_URL")
+        .env("HOME", home.path())
+        .env("XDG_CONFIG_HOME", home.path())
+        .env("XDG_CACHE_HOME", home.path())
+        .env("NO_PROXY", "*")
+        .output()
+        .unwrap();
+    eprintln!("stdout:\n{}", String::from_utf8_lossy(&output.stdout));
+    eprintln!("stderr:\n{}", String::from_utf8_lossy(&output.stderr));
+    output.status.code().unwrap()
+}
+
+fn read(basedir: &Path, path: &str) -> Vec<u8> {
+    std::fs::read(basedir.join(path)).unwrap()
+}
+
+#[test]
+fn fresh_install() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n"), ("data/blob.bin", &[0, 1, 2, 3, 255])]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 0);
+    assert_eq!(read(basedir.path(), "hello.txt"), b"Hello, world!\n");
+    assert_eq!(read(basedir.path(), "data/blob.bin"), [0, 1, 2, 3, 255]);
+}
+
+#[test]
+fn already_up_to_date() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n")]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    std::fs::write(basedir.path().join("hello.txt"), b"Hello, world!\n").unwrap();
+    // If we tried to download anything, it would fail.
+    server.put("hello.txt", b"");
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 0);
+    assert_eq!(read(basedir.path(), "hello.txt"), b"Hello, world!\n");
+}
+
+#[test]
+fn one_file_changed() {
+    let server = MockServer::start();
+    publish(&server, &[("same.txt", b"unchanged\n"), ("changed.txt", b"new contents\n")]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    std::fs::write(basedir.path().join("same.txt"), b"unchanged\n").unwrap();
+    std::fs::write(basedir.path().join("changed.txt"), b"old contents\n").unwrap();
+    server.put("same.txt", b"");
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 0);
+    assert_eq!(read(basedir.path(), "same.txt"), b"unchanged\n");
+    assert_eq!(read(basedir.path(), "changed.txt"), b"new contents\n");
+}
+
+#[test]
+fn obsolete_file_deleted() {
+    let server = MockServer::start();
+    publish(&server, &[("keep.txt", b"keep me\n")]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    std::fs::write(basedir.path().join("keep.txt"), b"keep me\n").unwrap();
+    std::fs::write(basedir.path().join("obsolete.txt"), b"delete me\n").unwrap();
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 0);
+    assert_eq!(read(basedir.path(), "keep.txt"), b"keep me\n");
+    assert!(!basedir.path().join("obsolete.txt").exists());
+}
+
+#[test]
+fn verify_reports_mismatch() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n")]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    std::fs::write(basedir.path().join("hello.txt"), b"Goodbye, world!\n").unwrap();
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--verify"]), 1);
+    assert_eq!(read(basedir.path(), "hello.txt"), b"Goodbye, world!\n");
+}
+
+#[test]
+fn verify_without_catalog_fails_with_2() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n")]);
+    server.files.lock().unwrap().remove("/main.cat");
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--verify"]), 2);
+}
+
+#[test]
+fn verify_fails_like_an_update() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n")]);
+    server.put("main.cat", b"not a catalog".to_vec());
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    // A catalog error, whether or not we're only verifying.
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--verify"]), 3);
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 3);
+}
+
+#[test]
+fn dry_run_reports_changes_like_check() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n")]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--dry-run"]), 1);
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--check"]), 1);
+    assert!(!basedir.path().join("hello.txt").exists());
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 0);
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--dry-run"]), 0);
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--check"]), 0);
+}
+
+#[test]
+fn max_total_download_exceeded() {
+    let server = MockServer::start();
+    publish(&server, &[("hello.txt", b"Hello, world!\n")]);
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    assert_eq!(run_tupdate_with(&server, &home, basedir.path(), &["--max-total-download", "10"]), 8);
+    assert!(!basedir.path().join("hello.txt").exists());
+}
+
+#[test]
+fn absurd_catalog_header_counts() {
+    let server = MockServer::start();
+    let mut extra_header = u32::MAX.to_be_bytes().to_vec();
+    extra_header.extend_from_slice(&u64::MAX.to_be_bytes());
+    server.put("index.lua", "basedir(basedir_override)\ninstall(\"a.cat\")\ninstall(\"b.cat\")\n");
+    server.put("a.cat", make_catalog_with_header(&[("a.txt", b"a")], b"\xFFTCaV\x02", &extra_header));
+    server.put("b.cat", make_catalog_with_header(&[("b.txt", b"b")], b"\xFFTCaV\x02", &extra_header));
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    // A catalog error, not an allocation failure or an overflow.
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 3);
+}
+
+#[test]
+fn conflicting_catalogs() {
+    let server = MockServer::start();
+    server.put("index.lua", "basedir(basedir_override)\ninstall(\"a.cat\")\ninstall(\"b.cat\")\n");
+    server.put("a.cat", make_catalog(&[("same.txt", b"one way")]));
+    server.put("b.cat", make_catalog(&[("same.txt", b"another way")]));
+    let home = TempDir::new();
+    let basedir = TempDir::new();
+    assert_eq!(run_tupdate(&server, &home, basedir.path()), 3);
+    assert!(!basedir.path().join("same.txt").exists());
+}